@@ -0,0 +1 @@
+ALTER TABLE "players" ADD COLUMN "last_map" VARCHAR;
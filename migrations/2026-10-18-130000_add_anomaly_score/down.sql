@@ -0,0 +1,2 @@
+ALTER TABLE "players" DROP COLUMN "flagged";
+ALTER TABLE "players" DROP COLUMN "anomaly_score";
@@ -0,0 +1,2 @@
+ALTER TABLE "players" ADD COLUMN "anomaly_score" REAL NOT NULL DEFAULT 0;
+ALTER TABLE "players" ADD COLUMN "flagged" BOOLEAN NOT NULL DEFAULT FALSE;
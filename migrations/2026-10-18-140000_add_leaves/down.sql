@@ -0,0 +1,4 @@
+ALTER TABLE "players" DROP COLUMN "penalty_until";
+ALTER TABLE "players" DROP COLUMN "leave_streak";
+ALTER TABLE "players" DROP COLUMN "surrenders";
+ALTER TABLE "players" DROP COLUMN "leaves";
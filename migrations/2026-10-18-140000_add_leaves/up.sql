@@ -0,0 +1,4 @@
+ALTER TABLE "players" ADD COLUMN "leaves" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "players" ADD COLUMN "surrenders" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "players" ADD COLUMN "leave_streak" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "players" ADD COLUMN "penalty_until" TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
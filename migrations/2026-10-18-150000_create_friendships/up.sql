@@ -0,0 +1,10 @@
+CREATE TABLE "friendships" (
+    "player_id" BIGINT NOT NULL REFERENCES "players" ("id") ON DELETE CASCADE,
+    "friend_id" BIGINT NOT NULL REFERENCES "players" ("id") ON DELETE CASCADE,
+    "accepted" BOOLEAN NOT NULL,
+    "created" TIMESTAMP NOT NULL,
+    PRIMARY KEY ("player_id", "friend_id")
+);
+
+CREATE INDEX "friendships_friend_id" ON "friendships" ("friend_id");
+CREATE UNIQUE INDEX "friendships_pair" ON "friendships" (LEAST("player_id", "friend_id"), GREATEST("player_id", "friend_id"));
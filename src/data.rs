@@ -1,5 +1,6 @@
 mod chest;
 mod daily_item;
+mod friend;
 mod map;
 mod player;
 mod tank;
@@ -8,11 +9,12 @@ mod tank_info;
 use chrono::NaiveDateTime;
 use quinn::Connection;
 use rand::Rng;
-use std::collections::HashMap;
-use strum::Display;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use strum::{Display, EnumString};
 
 pub use chest::*;
 pub use daily_item::*;
+pub use friend::*;
 pub use map::*;
 pub use player::*;
 pub use tank::*;
@@ -30,11 +32,12 @@ pub static TANKS: state::Storage<Vec<TankInfo>> = state::Storage::new();
 
 pub static NICKNAME_REGEX: state::Storage<regex::Regex> = state::Storage::new();
 
+pub static CONFIG: state::Storage<Config> = state::Storage::new();
+
 pub static MATCHMAKER: state::LocalStorage<flume::Sender<BalancerCommand>> =
     state::LocalStorage::new();
 
-pub static PHYSICS: state::LocalStorage<flume::Sender<PhysicsCommand>> =
-    state::LocalStorage::new();
+pub static PHYSICS: state::LocalStorage<flume::Sender<PhysicsCommand>> = state::LocalStorage::new();
 
 #[derive(Debug, Serialize, Deserialize, Display)]
 pub enum Packet {
@@ -72,6 +75,32 @@ pub enum Packet {
         error: Option<String>,
     },
 
+    FriendsListRequest,
+    //Nicknames of players who sent requests and of players requests were sent to
+    FriendsListResponse {
+        friends: Vec<Friend>,
+        incoming: Vec<String>,
+        outgoing: Vec<String>,
+    },
+    //Accepts the request if the player already sent one
+    AddFriendRequest {
+        nickname: String,
+    },
+    AcceptFriendRequest {
+        nickname: String,
+    },
+    DeclineFriendRequest {
+        nickname: String,
+    },
+    //Removes a friend or cancels a sent request
+    RemoveFriendRequest {
+        nickname: String,
+    },
+    FriendResponse {
+        nickname: String,
+        error: Option<String>,
+    },
+
     GetChestRequest {
         name: ChestName,
     },
@@ -105,35 +134,134 @@ pub enum Packet {
 
     JoinMatchMakerRequest {
         id: i32,
+        #[serde(default)]
+        mode: BattleMode,
+    },
+    //Player left too many battles and can't join for `time_left` seconds
+    MatchMakerPenaltyResponse {
+        time_left: u32,
+    },
+
+    //Battle time is in seconds, both are chosen by the matchmaker if not set
+    CreatePrivateMatchRequest {
+        id: i32,
+        mode: BattleMode,
+        map: Option<String>,
+        battle_time: Option<u16>,
+    },
+    //Code friends join with
+    CreatePrivateMatchResponse {
+        code: Option<String>,
+        error: Option<String>,
+    },
+    JoinPrivateMatchRequest {
+        code: String,
+        id: i32,
+    },
+    JoinPrivateMatchResponse {
+        error: Option<String>,
+    },
+    //Battle starts once the lobby is full, leaving it works like leaving the matchmaker
+    PrivateMatchJoinedResponse {
+        nickname: Option<String>,
     },
+    PrivateMatchClosedResponse,
 
     MapFoundResponse {
         wait_time: f32,
         map: Map,
-        opponent_nick: String,
-        opponent_tank: Tank,
+        mode: BattleMode,
+        my_team: u8,
         my_tank: Tank,
+        participants: Vec<BattleParticipant>,
         initial_packet: GamePacket,
+        //Indices in the map objects, for reconnected players
+        #[serde(default)]
+        destroyed_objects: Vec<u32>,
     },
     MapNotFoundResponse,
-    
+
+    ReplayRequest {
+        id: i64,
+    },
+    ReplayNotFoundResponse,
+    ReplayEndResponse {
+        result: Option<BattleResultStruct>,
+    },
+
+    //Battle of the player with given id, or a featured one
+    SpectateRequest {
+        id: Option<i64>,
+    },
+    SpectateResponse {
+        map: Map,
+        mode: BattleMode,
+        participants: Vec<BattleParticipant>,
+        initial_packet: SpectatorPacket,
+        #[serde(default)]
+        destroyed_objects: Vec<u32>,
+    },
+    SpectateNotFoundResponse,
+
     //Without responses
     LeaveMatchMakerRequest,
+    StopSpectatingRequest,
     Shoot,
+    UseAbility,
+    Surrender,
+    //Asks opponents for a rematch, or accepts the one they asked for
+    RematchRequest,
+    DeclineRematchRequest,
+    //Id of an emote from the catalogue, relayed to others in the battle
+    SendEmote {
+        id: u16,
+    },
+    //Emotes of the player with given nickname are not sent until unmuted
+    MutePlayer {
+        nickname: String,
+        muted: bool,
+    },
+    Emote {
+        nickname: String,
+        id: u16,
+    },
     Explosion {
         x: f32,
         y: f32,
         hit: bool,
     },
+    //Index of the object in the map objects
+    ObjectDestroyed {
+        index: u32,
+    },
+    PickupSpawned {
+        id: u32,
+        kind: PickupKind,
+        x: f32,
+        y: f32,
+    },
+    PickupCollected {
+        id: u32,
+        player_id: i64,
+    },
 
     //Without requests
     BattleResultResponse {
         result: BattleResultStruct,
         profile: Player,
+        replay_id: Option<i64>,
+    },
+    SpectateEndResponse {
+        winner_team: Option<u8>,
+    },
+    //Player with given id asks for a rematch
+    RematchOfferResponse {
+        player_id: i64,
     },
+    RematchDeclinedResponse,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BattleResultStruct {
     pub result: BattleResult,
     pub trophies: i32,
@@ -145,33 +273,140 @@ pub struct BattleResultStruct {
     pub efficiency: f32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub enum BattleResult {
     Draw,
     Victory,
     Defeat,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Display)]
+pub enum BattleMode {
+    #[default]
+    Duel,
+    TwoVsTwo,
+    ThreeVsThree,
+    FreeForAll,
+}
+
+impl BattleMode {
+    pub fn players_count(&self) -> usize {
+        match *self {
+            Self::Duel => 2,
+            Self::TwoVsTwo => 4,
+            Self::ThreeVsThree => 6,
+            Self::FreeForAll => 4,
+        }
+    }
+
+    pub fn teams_count(&self) -> usize {
+        match *self {
+            Self::FreeForAll => self.players_count(),
+            _ => 2,
+        }
+    }
+
+    //Players are assigned to teams by their slot in the battle: 0, 1, 0, 1...
+    pub fn team_of(&self, index: usize) -> u8 {
+        (index % self.teams_count()) as u8
+    }
+}
+
+//Other battle member as seen by the client, in the same order as `GamePacket::others_data`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BattleParticipant {
+    pub nickname: String,
+    pub tank: Tank,
+    pub team: u8,
+}
+
 //This packet server sends to client
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GamePacket {
     pub time_left: u16,
     pub frame_num: u16,
     pub my_data: GamePlayerData,
-    pub opponent_data: GamePlayerData,
+    pub others_data: Vec<GamePlayerData>,
+    //`time_left` is the time of the phase
+    #[serde(default)]
+    pub phase: BattlePhase,
 }
 
-//This packet client sends to server
+//This datagram server sends to every player each tick instead of `GamePacket`.
+//Values are quantised (positions in quarters of a pixel, rotations in 1/65536
+//of a turn, cool down in hundredths of a second) and are differences from the
+//snapshot of `base_frame`, or from zero if it is None. Client acknowledges
+//received frames with `PlayerPosition::ack_frame` and keeps them until a newer
+//one is used as a base
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotPacket {
+    pub frame_num: u16,
+    pub base_frame: Option<u16>,
+    pub time_left: u16,
+    //Own tank first, then others in the same order as in `GamePacket`
+    pub players: Vec<PlayerDelta>,
+    //Bullets that are not listed did not change since the base
+    pub bullets: Vec<BulletDelta>,
+    pub removed_bullets: Vec<u32>,
+    #[serde(default)]
+    pub phase: BattlePhase,
+}
+
+//None if value did not change
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerDelta {
+    pub x: Option<i32>,
+    pub y: Option<i32>,
+    pub body_rotation: Option<i32>,
+    pub gun_rotation: Option<i32>,
+    pub hp: Option<i32>,
+    pub cool_down: Option<i32>,
+    pub visible: Option<bool>,
+    //0 without effect, otherwise `PickupKind` + 1
+    pub effect: Option<u8>,
+    //In hundredths of a second like cool down
+    pub effect_time: Option<i32>,
+    pub ability_time: Option<i32>,
+    pub ability_cool_down: Option<i32>,
+}
+
+//`owner` is an index in `SnapshotPacket::players`, it is set only for new bullets
 #[derive(Debug, Serialize, Deserialize)]
+pub struct BulletDelta {
+    pub id: u32,
+    pub owner: Option<u8>,
+    pub x: i32,
+    pub y: i32,
+    pub rotation: i32,
+}
+
+//This packet server sends to spectators, `players_data` is ordered like
+//`SpectateResponse::participants`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpectatorPacket {
+    pub time_left: u16,
+    pub frame_num: u16,
+    pub players_data: Vec<GamePlayerData>,
+    #[serde(default)]
+    pub phase: BattlePhase,
+}
+
+//This packet client sends to server
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PlayerPosition {
     pub frame_num: u16,
     pub body_rotation: f32,
     pub gun_rotation: f32,
     pub moving: bool,
+    //`SnapshotPacket::frame_num` of the last state client has received
+    #[serde(default)]
+    pub ack_frame: Option<u16>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+//Hidden tanks have default values, except for their visible bullets
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct GamePlayerData {
+    pub visible: bool,
     pub x: f32,
     pub y: f32,
     pub body_rotation: f32,
@@ -179,10 +414,30 @@ pub struct GamePlayerData {
     pub hp: u16,
     pub cool_down: f32,
     pub bullets: Vec<BulletData>,
+    pub effect: Option<ActiveEffect>,
+    //Seconds left of the active ability and until it can be used again
+    pub ability_time: f32,
+    pub ability_cool_down: f32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PickupKind {
+    Repair,
+    ReloadBoost,
+    DamageBoost,
+    Shield,
+}
+
+//Temporary modifier of a picked up boost, repair is applied at once
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ActiveEffect {
+    pub kind: PickupKind,
+    pub time_left: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BulletData {
+    pub id: u32,
     pub x: f32,
     pub y: f32,
     pub rotation: f32,
@@ -194,14 +449,77 @@ pub enum BalancerCommand {
         player: Box<Player>,
         tank_id: i32,
         conn: Connection,
+        mode: BattleMode,
     },
     RemovePlayer(i64),
+    CreatePrivateMatch {
+        player: Box<Player>,
+        tank_id: i32,
+        conn: Connection,
+        mode: BattleMode,
+        settings: PrivateMatch,
+    },
+    JoinPrivateMatch {
+        player: Box<Player>,
+        tank_id: i32,
+        conn: Connection,
+        code: String,
+    },
+}
+
+//Settings of a battle arranged with an invite code. Players keep their
+//trophies, coins and xp
+#[derive(Debug, Clone, Default)]
+pub struct PrivateMatch {
+    pub map: Option<String>,
+    //Seconds, regular battle time if not set
+    pub battle_time: Option<u16>,
 }
 
 #[derive(Debug)]
 pub struct Client {
     pub id: i64,
 }
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub replays_dir: Option<PathBuf>,
+    pub bot_wait: Duration,
+    pub max_rewind: Duration,
+    //Threads simulating battles
+    pub workers: usize,
+    pub tick_rate: u32,
+    //State datagrams per second, at most `tick_rate`
+    pub send_rate: u32,
+    pub overtime: Overtime,
+    pub overtime_time: Duration,
+    pub reconnect_grace: Duration,
+}
+
+//What happens when battle time is over and several teams are alive
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, EnumString, Display,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Overtime {
+    //Draw right away
+    #[default]
+    None,
+    //Tanks outside of the arena lose hp, the arena shrinks to the map center
+    ShrinkingArena,
+    //Team that hits first wins
+    NextHit,
+    //Team with the highest part of its hp left wins, without extra time
+    HpTiebreak,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BattlePhase {
+    #[default]
+    Regular,
+    Overtime,
+}
+
 pub struct WeightedRandomList<T>
 where
     T: Copy + PartialEq,
@@ -0,0 +1,43 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::friendships;
+
+//Accepted friends and sent requests that are not answered yet
+pub const MAX_FRIENDS: usize = 100;
+pub const MAX_FRIEND_REQUESTS: usize = 50;
+
+//Request from `player_id` to `friend_id`, they are friends once it is accepted.
+//One row per pair, whoever sent the request
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[table_name = "friendships"]
+pub struct Friendship {
+    pub player_id: i64,
+    pub friend_id: i64,
+    pub accepted: bool,
+    pub created: NaiveDateTime,
+}
+
+impl Friendship {
+    //The other side of the friendship
+    pub fn other(&self, id: i64) -> i64 {
+        if self.player_id == id {
+            self.friend_id
+        } else {
+            self.player_id
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Offline,
+    Online,
+    InBattle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Friend {
+    pub nickname: String,
+    pub presence: Presence,
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use super::BattleMode;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct MapObject {
     pub id: i32,
@@ -13,9 +15,87 @@ pub struct Map {
     pub name: String,
     pub width: i32,
     pub height: i32,
-    #[serde(rename = "player1Y")]
+    //Rows of tanks for maps without spawn points
+    #[serde(default, rename = "player1Y")]
     pub player1_y: i32,
-    #[serde(rename = "player2Y")]
+    #[serde(default, rename = "player2Y")]
     pub player2_y: i32,
     pub objects: Vec<MapObject>,
+    //Pickups spawn at random free places if the map has no points
+    #[serde(default, rename = "pickupPoints")]
+    pub pickup_points: Vec<PickupPoint>,
+    #[serde(default)]
+    pub selection: MapSelection,
+    //Maps made before the version field are version 1
+    #[serde(default = "first_version")]
+    pub version: u32,
+    #[serde(default, rename = "spawnPoints")]
+    pub spawn_points: Vec<SpawnPoint>,
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+}
+
+//Newest format of map files the server can load
+pub const MAP_VERSION: u32 = 2;
+
+fn first_version() -> u32 {
+    1
+}
+
+//Rotation is in degrees, team is ignored in free-for-all
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct SpawnPoint {
+    pub x: f32,
+    pub y: f32,
+    pub rotation: f32,
+    pub team: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ZoneKind {
+    Capture,
+    Slow,
+    Hazard,
+}
+
+//Rectangle by its top left corner, in pixels
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Zone {
+    pub name: String,
+    pub kind: ZoneKind,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+//Which battles are played on the map, so new maps can be given to a part of players
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MapSelection {
+    pub enabled: bool,
+    //Relative chance among the maps a battle can be played on
+    pub weight: f32,
+    pub min_trophies: i32,
+    pub max_trophies: Option<i32>,
+    //Every mode if empty
+    pub modes: Vec<BattleMode>,
+}
+
+impl Default for MapSelection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            weight: 1f32,
+            min_trophies: 0,
+            max_trophies: None,
+            modes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct PickupPoint {
+    pub x: f32,
+    pub y: f32,
 }
@@ -8,6 +8,10 @@ use super::Tank;
 use super::{DailyItem, TankRarity, WeightedRandomList};
 use crate::schema::players;
 
+//Seconds of the matchmaking penalty for the second leave in a row
+const LEAVE_PENALTY: i64 = 60;
+const MAX_LEAVE_PENALTY: i64 = 60 * 60;
+
 #[derive(
     Serialize, Deserialize, Queryable, Insertable, Debug, AsChangeset, Identifiable, Clone,
 )]
@@ -53,6 +57,33 @@ pub struct Player {
     pub tanks: Vec<Tank>,
 
     pub daily_items: Vec<DailyItem>,
+
+    //Name of the map of the last battle, the next one is played on another map
+    #[serde(skip)]
+    pub last_map: Option<String>,
+
+    //Sum of the highest anomaly scores of player inputs in each battle
+    #[serde(skip)]
+    pub anomaly_score: f32,
+
+    //Set once inputs of a battle crossed the flag threshold
+    #[serde(skip)]
+    pub flagged: bool,
+
+    //Battles forfeited by not coming back in time
+    #[serde(skip)]
+    pub leaves: i32,
+
+    #[serde(skip)]
+    pub surrenders: i32,
+
+    //Recent leaves, each battle played to the end forgives one
+    #[serde(skip)]
+    pub leave_streak: i32,
+
+    //Player can't join the matchmaker until this time
+    #[serde(skip, default = "default_naive_date_time")]
+    pub penalty_until: NaiveDateTime,
 }
 
 pub fn default_naive_date_time() -> NaiveDateTime {
@@ -65,11 +96,22 @@ pub fn default_naive_date_time() -> NaiveDateTime {
 impl Player {
     pub fn new(id: i64, machine_id: String) -> Self {
         let mut res = Self {
-            id,
             machine_id,
+            nickname: None,
+            ..Self::unregistered(id, String::new())
+        };
+        res.daily_items = res.get_daily_items();
+        res
+    }
+
+    //Profile of a battle participant that is not stored in database (replays, bots)
+    pub fn unregistered(id: i64, nickname: String) -> Self {
+        Self {
+            id,
+            machine_id: String::new(),
             reg_date: Utc::now().naive_utc(),
             last_online: Utc::now().naive_utc(),
-            nickname: None,
+            nickname: Some(nickname),
             battles_count: 0,
             victories_count: 0,
             xp: 0,
@@ -84,9 +126,44 @@ impl Player {
             trophies: 0,
             tanks: Vec::new(),
             daily_items: Vec::new(),
-        };
-        res.daily_items = res.get_daily_items();
-        res
+            last_map: None,
+            anomaly_score: 0f32,
+            flagged: false,
+            leaves: 0,
+            surrenders: 0,
+            leave_streak: 0,
+            penalty_until: default_naive_date_time(),
+        }
+    }
+
+    pub fn add_leave(&mut self) {
+        self.leaves += 1;
+        self.extend_leave_streak();
+    }
+
+    //Surrender gives the battle away like a leave, so it counts for the streak too
+    pub fn add_surrender(&mut self) {
+        self.surrenders += 1;
+        self.extend_leave_streak();
+    }
+
+    //First leave in a row is forgiven, every next one doubles the penalty
+    fn extend_leave_streak(&mut self) {
+        self.leave_streak += 1;
+        if self.leave_streak > 1 {
+            let seconds = (LEAVE_PENALTY << (self.leave_streak - 2).min(16)).min(MAX_LEAVE_PENALTY);
+            self.penalty_until = Utc::now().naive_utc() + chrono::Duration::seconds(seconds);
+        }
+    }
+
+    pub fn add_finished_battle(&mut self) {
+        self.leave_streak = 0.max(self.leave_streak - 1);
+    }
+
+    //Seconds until the player can join the matchmaker again
+    pub fn penalty_left(&self) -> Option<u32> {
+        let seconds = (self.penalty_until - Utc::now().naive_utc()).num_seconds();
+        (seconds > 0).then_some(seconds as u32)
     }
 
     pub fn get_efficiency(&self) -> f32 {
@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use strum::EnumIter;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TankInfo {
     pub id: u32,
@@ -9,7 +9,7 @@ pub struct TankInfo {
     pub characteristics: TankCharacteristics,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TankGraphicsInfo {
     pub bullet_name: String,
@@ -25,7 +25,7 @@ pub struct TankGraphicsInfo {
     pub tank_height: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TankCharacteristics {
     pub name: String,
@@ -37,9 +37,45 @@ pub struct TankCharacteristics {
     pub reloading: f32,
     pub bullet_speed: f32,
     pub damage: f32,
+    //In pixels, bullets fly until they hit something if not set
+    #[serde(default)]
+    pub bullet_range: Option<f32>,
+    //Bullet bounces off walls and map objects hit at an angle to their
+    //surface below `ricochet_angle` degrees, at most `ricochets` times
+    #[serde(default)]
+    pub ricochets: u32,
+    #[serde(default)]
+    pub ricochet_angle: f32,
+    //Enemies within the radius in pixels of the explosion are damaged too,
+    //down to `1 - splash_falloff` of the damage at its edge
+    #[serde(default)]
+    pub splash_radius: f32,
+    #[serde(default)]
+    pub splash_falloff: f32,
+    #[serde(default)]
+    pub ability: Option<TankAbility>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, EnumIter)]
+//Cool down starts when the ability is used, both are in seconds
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TankAbility {
+    pub kind: AbilityKind,
+    pub cool_down: f32,
+    pub duration: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AbilityKind {
+    //Hides the tank like a bush does
+    SmokeScreen,
+    SpeedBurst,
+    Shield,
+    //Every shot fires three bullets
+    TripleShot,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, EnumIter, Clone)]
 pub enum TankRarity {
     COMMON,
     RARE,
@@ -2,7 +2,10 @@ use parking_lot::Mutex;
 
 use diesel::PgConnection;
 
-use crate::{data::Player, schema::players::dsl::*};
+use crate::{
+    data::{Friendship, Player},
+    schema::{friendships, players::dsl::*},
+};
 use diesel::prelude::*;
 
 pub static POOL: state::LocalStorage<PgConnection> = state::LocalStorage::new();
@@ -51,3 +54,95 @@ pub fn update_player(player: &Player) -> color_eyre::Result<()> {
 
     Ok(())
 }
+
+//Only columns a battle changes, the rest of the profile could have been
+//changed since the battle started
+pub fn save_battle_stats(player: &Player) -> color_eyre::Result<()> {
+    let conn = POOL.try_get().unwrap();
+    diesel::update(players.find(player.id))
+        .set((
+            battles_count.eq(player.battles_count),
+            victories_count.eq(player.victories_count),
+            xp.eq(player.xp),
+            rank_level.eq(player.rank_level),
+            coins.eq(player.coins),
+            accuracy.eq(player.accuracy),
+            damage_dealt.eq(player.damage_dealt),
+            damage_taken.eq(player.damage_taken),
+            trophies.eq(player.trophies),
+            last_map.eq(&player.last_map),
+            anomaly_score.eq(player.anomaly_score),
+            flagged.eq(player.flagged),
+            leaves.eq(player.leaves),
+            surrenders.eq(player.surrenders),
+            leave_streak.eq(player.leave_streak),
+            penalty_until.eq(player.penalty_until),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+//Friendships and requests the player is on either side of
+pub fn get_friendships(player: i64) -> color_eyre::Result<Vec<Friendship>> {
+    let conn = POOL.try_get().unwrap();
+    let res = friendships::table
+        .filter(
+            friendships::player_id
+                .eq(player)
+                .or(friendships::friend_id.eq(player)),
+        )
+        .load(conn)?;
+    Ok(res)
+}
+
+//Returns false if the players are already on either side of a friendship or request
+pub fn save_friendship(friendship: &Friendship) -> color_eyre::Result<bool> {
+    let conn = POOL.try_get().unwrap();
+    let inserted = diesel::insert_into(friendships::table)
+        .values(friendship)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(inserted == 1)
+}
+
+pub fn accept_friendship(from: i64, to: i64) -> color_eyre::Result<()> {
+    let conn = POOL.try_get().unwrap();
+    diesel::update(friendships::table.find((from, to)))
+        .set(friendships::accepted.eq(true))
+        .execute(conn)?;
+    Ok(())
+}
+
+//Removes the friendship or request between two players, whoever sent it
+pub fn delete_friendship(first: i64, second: i64) -> color_eyre::Result<()> {
+    let conn = POOL.try_get().unwrap();
+    diesel::delete(
+        friendships::table.filter(
+            (friendships::player_id
+                .eq(first)
+                .and(friendships::friend_id.eq(second)))
+            .or(friendships::player_id
+                .eq(second)
+                .and(friendships::friend_id.eq(first))),
+        ),
+    )
+    .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_nicknames(ids: &[i64]) -> color_eyre::Result<Vec<(i64, Option<String>)>> {
+    let conn = POOL.try_get().unwrap();
+    let res = players
+        .filter(id.eq_any(ids))
+        .select((id, nickname))
+        .load(conn)?;
+    Ok(res)
+}
+
+pub fn set_friends_nicks(player: i64, nicks: &[String]) -> color_eyre::Result<()> {
+    let conn = POOL.try_get().unwrap();
+    diesel::update(players.find(player))
+        .set(friends_nicks.eq(nicks))
+        .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+
+use color_eyre::eyre::Result;
+
+use crate::{
+    data::{Friend, Friendship, Player, Presence, CLIENTS, MAX_FRIENDS, MAX_FRIEND_REQUESTS},
+    db, physics,
+};
+
+//Friend requests are sent by nickname. Functions return the error shown to
+//the player, database errors are returned as `Err`
+
+pub fn add(id: i64, nickname: &str) -> Result<Option<&'static str>> {
+    let target = match db::get_player_by_nickname(nickname) {
+        Some(target) if target.id != id => target,
+        Some(_) => return Ok(Some("Can't add yourself")),
+        None => return Ok(Some("Player not found")),
+    };
+    let friendships = db::get_friendships(id)?;
+    match request(id, target.id, &friendships) {
+        Ok(Request::Accept) => accept(id, nickname),
+        Ok(Request::Send) => {
+            let saved = db::save_friendship(&Friendship {
+                player_id: id,
+                friend_id: target.id,
+                accepted: false,
+                created: chrono::Utc::now().naive_utc(),
+            })?;
+            if !saved {
+                //Target sent a request at the same time, so this one accepts it
+                return add(id, nickname);
+            }
+            Ok(None)
+        }
+        Err(e) => Ok(Some(e)),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Send,
+    //Both want to be friends
+    Accept,
+}
+
+//What a friend request to `target` does, given friendships of the sender
+fn request(
+    id: i64,
+    target: i64,
+    friendships: &[Friendship],
+) -> std::result::Result<Request, &'static str> {
+    match friendships.iter().find(|f| f.other(id) == target) {
+        Some(f) if f.accepted => return Err("Already friends"),
+        Some(f) if f.player_id == id => return Err("Request already sent"),
+        Some(_) => return Ok(Request::Accept),
+        None => {}
+    }
+    if friends_count(friendships) >= MAX_FRIENDS {
+        return Err("Friends list is full");
+    }
+    let sent = friendships
+        .iter()
+        .filter(|f| !f.accepted && f.player_id == id)
+        .count();
+    if sent >= MAX_FRIEND_REQUESTS {
+        return Err("Too many requests sent");
+    }
+    Ok(Request::Send)
+}
+
+pub fn accept(id: i64, nickname: &str) -> Result<Option<&'static str>> {
+    let target = match incoming_request(id, nickname)? {
+        Some(target) => target,
+        None => return Ok(Some("No request from this player")),
+    };
+    for player in [id, target.id] {
+        if friends_count(&db::get_friendships(player)?) >= MAX_FRIENDS {
+            return Ok(Some("Friends list is full"));
+        }
+    }
+    db::accept_friendship(target.id, id)?;
+    sync_friends_nicks(id)?;
+    sync_friends_nicks(target.id)?;
+    Ok(None)
+}
+
+pub fn decline(id: i64, nickname: &str) -> Result<Option<&'static str>> {
+    match incoming_request(id, nickname)? {
+        Some(target) => {
+            db::delete_friendship(id, target.id)?;
+            Ok(None)
+        }
+        None => Ok(Some("No request from this player")),
+    }
+}
+
+//Removes a friend or cancels a sent request
+pub fn remove(id: i64, nickname: &str) -> Result<Option<&'static str>> {
+    let target = match db::get_player_by_nickname(nickname) {
+        Some(target) => target,
+        None => return Ok(Some("Player not found")),
+    };
+    let friendships = db::get_friendships(id)?;
+    let friendship = friendships
+        .iter()
+        .find(|f| f.other(id) == target.id && (f.accepted || f.player_id == id));
+    match friendship {
+        Some(friendship) => {
+            db::delete_friendship(id, target.id)?;
+            if friendship.accepted {
+                sync_friends_nicks(id)?;
+                sync_friends_nicks(target.id)?;
+            }
+            Ok(None)
+        }
+        None => Ok(Some("Not friends")),
+    }
+}
+
+//Friends with presence, nicknames of players who sent requests and of
+//players requests were sent to
+pub fn list(id: i64) -> Result<(Vec<Friend>, Vec<String>, Vec<String>)> {
+    let friendships = db::get_friendships(id)?;
+    let ids: Vec<i64> = friendships.iter().map(|f| f.other(id)).collect();
+    let nicknames = db::get_nicknames(&ids)?;
+    let nickname = |other: i64| {
+        nicknames
+            .iter()
+            .find(|f| f.0 == other)
+            .and_then(|f| f.1.clone())
+    };
+    let online: HashSet<i64> = CLIENTS.get().iter().map(|f| f.id).collect();
+
+    let (mut friends, mut incoming, mut outgoing) = (Vec::new(), Vec::new(), Vec::new());
+    for friendship in &friendships {
+        let other = friendship.other(id);
+        let nickname = match nickname(other) {
+            Some(nickname) => nickname,
+            None => continue,
+        };
+        if friendship.accepted {
+            let presence = if physics::in_battle(other) {
+                Presence::InBattle
+            } else if online.contains(&other) {
+                Presence::Online
+            } else {
+                Presence::Offline
+            };
+            friends.push(Friend { nickname, presence });
+        } else if friendship.friend_id == id {
+            incoming.push(nickname);
+        } else {
+            outgoing.push(nickname);
+        }
+    }
+    friends.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+    Ok((friends, incoming, outgoing))
+}
+
+//Request the player got from the one with given nickname
+fn incoming_request(id: i64, nickname: &str) -> Result<Option<Player>> {
+    let target = match db::get_player_by_nickname(nickname) {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+    let requested = db::get_friendships(id)?
+        .iter()
+        .any(|f| !f.accepted && f.player_id == target.id && f.friend_id == id);
+    Ok(requested.then_some(target))
+}
+
+fn friends_count(friendships: &[Friendship]) -> usize {
+    friendships.iter().filter(|f| f.accepted).count()
+}
+
+//`friends_nicks` of the profile follows the friendships table
+fn sync_friends_nicks(id: i64) -> Result<()> {
+    let (friends, ..) = list(id)?;
+    let nicks: Vec<String> = friends.into_iter().map(|f| f.nickname).collect();
+    db::set_friends_nicks(id, &nicks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn friendship(player_id: i64, friend_id: i64, accepted: bool) -> Friendship {
+        Friendship {
+            player_id,
+            friend_id,
+            accepted,
+            created: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn test_mutual_requests_are_accepted() {
+        let friendships = vec![friendship(2, 1, false), friendship(1, 3, false)];
+        assert_eq!(request(1, 2, &friendships), Ok(Request::Accept));
+        assert_eq!(request(1, 3, &friendships), Err("Request already sent"));
+        assert_eq!(request(1, 4, &friendships), Ok(Request::Send));
+        let friendships = vec![friendship(2, 1, true)];
+        assert_eq!(request(1, 2, &friendships), Err("Already friends"));
+    }
+
+    #[test]
+    fn test_friends_and_requests_are_limited() {
+        let mut friendships: Vec<Friendship> = (0..MAX_FRIEND_REQUESTS as i64)
+            .map(|f| friendship(1, f + 10, false))
+            .collect();
+        assert_eq!(request(1, 2, &friendships), Err("Too many requests sent"));
+        //Requests of others don't count
+        friendships[0] = friendship(10, 1, false);
+        assert_eq!(request(1, 2, &friendships), Ok(Request::Send));
+
+        let friendships: Vec<Friendship> = (0..MAX_FRIENDS as i64)
+            .map(|f| friendship(f + 10, 1, true))
+            .collect();
+        assert_eq!(request(1, 2, &friendships), Err("Friends list is full"));
+    }
+}
@@ -1,17 +1,18 @@
 use crate::{
     data::{
-        self, BalancerCommand, Chest, ChestName, Client, Player, PlayerPosition, CLIENTS,
-        MATCHMAKER, NICKNAME_REGEX, PHYSICS,
+        self, BalancerCommand, BattleMode, Chest, ChestName, Client, Player, PlayerPosition,
+        PrivateMatch, CLIENTS, CONFIG, MATCHMAKER, NICKNAME_REGEX, PHYSICS,
     },
-    db,
+    db, friends,
     physics::{self, BalancedPlayer, PhysicsCommand},
 };
 
-use std::{io::Cursor, str::FromStr, sync::Arc};
+use std::{collections::HashMap, io::Cursor, str::FromStr, sync::Arc, time::Duration};
 
 use color_eyre::eyre::{bail, eyre, Result};
 use futures::{StreamExt, TryFutureExt};
 use minstant::Instant;
+use rand::Rng;
 use rmp_serde::{Deserializer, Serializer};
 use rustls::{Certificate, PrivateKey};
 use serde::{Deserialize, Serialize};
@@ -21,6 +22,33 @@ use tracing::{debug, error, info, info_span, warn, Instrument};
 pub const ALPN_QUIC_TANK_WARS: &[&[u8]] = &[b"tank-wars-prot"];
 pub const EXPECTED_MTU: usize = 1350;
 pub const TWELVE_HOURS: i64 = 12 * 60 * 60;
+//Invite codes are short and have no look-alike characters, so they are easy to dictate
+const CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 6;
+//Private lobbies that don't fill up in time are closed
+const PRIVATE_LOBBY_TIME: Duration = Duration::from_secs(10 * 60);
+//Seconds, length of private battles
+const MIN_PRIVATE_TIME: u16 = 30;
+const MAX_PRIVATE_TIME: u16 = 10 * 60;
+
+//Players waiting for a private match to fill up, the first one created it
+struct PrivateLobby {
+    mode: BattleMode,
+    settings: PrivateMatch,
+    players: Vec<BalancedPlayer>,
+    created: Instant,
+}
+
+impl PrivateLobby {
+    fn notify(&self, packet: &data::Packet) {
+        for player in &self.players {
+            if let Some(conn) = &player.2 {
+                Server::notify(conn, packet);
+            }
+        }
+    }
+}
+
 pub struct Server {
     port: u16,
     key_log: bool,
@@ -41,7 +69,7 @@ impl Server {
 
         //Balancer initialization
         let (send, recv) = flume::unbounded::<BalancerCommand>();
-        let p_send = physics::start();
+        let p_send = physics::start()?;
         MATCHMAKER.set(move || send.clone());
         PHYSICS.set(move || p_send.clone());
         tokio::spawn(async move {
@@ -96,65 +124,236 @@ impl Server {
         Ok(())
     }
 
-    //Simple balancer, gets two players with diff <= 60.
-    //Prefers player with lowest number
+    //Simple balancer, keeps a queue per battle mode and gets `players_count` players
+    //whose trophies differ by <= 60. Prefers the group with the lowest number
     //Number only increments, so it is limited to i32::MAX_VALUE
+    //Players that wait longer than `bot_wait` get bots for the rest of the seats
     //TODO: improve
     async fn balance_players(recv: flume::Receiver<BalancerCommand>) -> Result<()> {
         const DIFF: u32 = 60;
+        const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+        let bot_wait = CONFIG.get().bot_wait;
         let mut number = 0;
-        let mut list: Vec<(BalancedPlayer, i32)> = Vec::new();
-        while let Ok(x) = recv.recv_async().await {
-            match x {
-                BalancerCommand::AddPlayer {
+        let mut lists: HashMap<BattleMode, Vec<(BalancedPlayer, i32, Instant)>> = HashMap::new();
+        //By invite code
+        let mut private: HashMap<String, PrivateLobby> = HashMap::new();
+        loop {
+            let command = match tokio::time::timeout(CHECK_INTERVAL, recv.recv_async()).await {
+                Ok(Ok(command)) => Some(command),
+                Ok(Err(_)) => break,
+                Err(_) => None,
+            };
+            match command {
+                Some(BalancerCommand::AddPlayer {
                     player,
                     tank_id,
                     conn,
-                } => {
-                    if list.iter().any(|f| f.0 .0.id == player.id) {
+                    mode,
+                }) => {
+                    if Self::is_waiting(&lists, &private, player.id) {
                         continue;
                     }
-                    list.push((BalancedPlayer(player, tank_id, conn), number));
+                    let list = lists.entry(mode).or_default();
+                    list.push((
+                        BalancedPlayer(player, tank_id, Some(conn)),
+                        number,
+                        Instant::now(),
+                    ));
                     number += 1;
-                    list.sort_by(|a, b| a.0 .0.trophies.cmp(&b.0 .0.trophies));
-                    for i in 0..list.len() {
-                        let mut best_match: Option<&(BalancedPlayer, i32)> = None;
-                        for j in (i + 1)..list.len() {
-                            if list[j].0 .0.trophies.abs_diff(list[i].0 .0.trophies) <= DIFF {
-                                if best_match.is_none() || list[j].1 < best_match.unwrap().1 {
-                                    best_match = Some(&list[j]);
-                                }
-                            } else {
-                                break;
+                    list.sort_by_key(|f| f.0 .0.trophies);
+
+                    let count = mode.players_count();
+                    let best_match = list
+                        .windows(count)
+                        .enumerate()
+                        .filter(|(_, window)| {
+                            let (lowest, highest) = (&window[0].0 .0, &window[count - 1].0 .0);
+                            highest.trophies.abs_diff(lowest.trophies) <= DIFF
+                        })
+                        .map(|(i, window)| (i, window.iter().map(|f| f.1).min().unwrap()))
+                        .min_by_key(|f| f.1);
+                    if let Some((i, _)) = best_match {
+                        let mut players: Vec<BalancedPlayer> =
+                            list.drain(i..i + count).map(|f| f.0).collect();
+                        //Snake draft by trophies, so teams get equal strength
+                        for (k, chunk) in players.chunks_mut(mode.teams_count()).enumerate() {
+                            if k % 2 == 1 {
+                                chunk.reverse();
                             }
                         }
-                        if best_match.is_some() {
-                            let index = list
-                                .iter()
-                                .position(|f| f.0 .0.id == list[i].0 .0.id)
-                                .unwrap();
-                            let id = best_match.unwrap().0 .0.id;
-                            let player1 = list.remove(index).0;
-                            let index = list.iter().position(|f| f.0 .0.id == id).unwrap();
-                            let player2 = list.remove(index).0;
-                            PHYSICS
-                                .get()
-                                .send(physics::PhysicsCommand::CreateMatch {
-                                    players: (player1, player2),
-                                })
-                                .unwrap();
+                        PHYSICS
+                            .get()
+                            .send(physics::PhysicsCommand::CreateMatch {
+                                players,
+                                mode,
+                                private: None,
+                            })
+                            .unwrap();
+                    }
+                }
+                Some(BalancerCommand::RemovePlayer(id)) => {
+                    for list in lists.values_mut() {
+                        list.retain(|f| f.0 .0.id != id);
+                    }
+                    //Lobby is closed when its creator leaves
+                    private.retain(|_, lobby| {
+                        if lobby.players[0].0.id == id {
+                            lobby.players.remove(0);
+                            lobby.notify(&data::Packet::PrivateMatchClosedResponse);
+                            return false;
                         }
+                        lobby.players.retain(|f| f.0.id != id);
+                        true
+                    });
+                }
+                Some(BalancerCommand::CreatePrivateMatch {
+                    player,
+                    tank_id,
+                    conn,
+                    mode,
+                    settings,
+                }) => {
+                    if Self::is_waiting(&lists, &private, player.id) {
+                        let packet = data::Packet::CreatePrivateMatchResponse {
+                            code: None,
+                            error: Some("Already waiting for a battle".to_owned()),
+                        };
+                        Self::notify(&conn, &packet);
+                        continue;
                     }
+                    let code = loop {
+                        let code = Self::invite_code();
+                        if !private.contains_key(&code) {
+                            break code;
+                        }
+                    };
+                    let packet = data::Packet::CreatePrivateMatchResponse {
+                        code: Some(code.clone()),
+                        error: None,
+                    };
+                    Self::notify(&conn, &packet);
+                    let lobby = PrivateLobby {
+                        mode,
+                        settings,
+                        players: vec![BalancedPlayer(player, tank_id, Some(conn))],
+                        created: Instant::now(),
+                    };
+                    private.insert(code, lobby);
                 }
-                BalancerCommand::RemovePlayer(id) => {
-                    if let Some(index) = list.iter().position(|f| f.0 .0.id == id) {
-                        list.remove(index);
+                Some(BalancerCommand::JoinPrivateMatch {
+                    player,
+                    tank_id,
+                    conn,
+                    code,
+                }) => {
+                    let code = code.to_uppercase();
+                    let error = if Self::is_waiting(&lists, &private, player.id) {
+                        Some("Already waiting for a battle")
+                    } else if !private.contains_key(&code) {
+                        Some("Wrong code")
+                    } else {
+                        None
+                    };
+                    let packet = data::Packet::JoinPrivateMatchResponse {
+                        error: error.map(str::to_owned),
+                    };
+                    Self::notify(&conn, &packet);
+                    if error.is_some() {
+                        continue;
+                    }
+
+                    let lobby = private.get_mut(&code).unwrap();
+                    lobby.notify(&data::Packet::PrivateMatchJoinedResponse {
+                        nickname: player.nickname.clone(),
+                    });
+                    lobby
+                        .players
+                        .push(BalancedPlayer(player, tank_id, Some(conn)));
+                    if lobby.players.len() == lobby.mode.players_count() {
+                        let lobby = private.remove(&code).unwrap();
+                        PHYSICS
+                            .get()
+                            .send(physics::PhysicsCommand::CreateMatch {
+                                players: lobby.players,
+                                mode: lobby.mode,
+                                private: Some(lobby.settings),
+                            })
+                            .unwrap();
                     }
                 }
+                None => {}
+            }
+
+            private.retain(|_, lobby| {
+                if lobby.created.elapsed() >= PRIVATE_LOBBY_TIME {
+                    lobby.notify(&data::Packet::PrivateMatchClosedResponse);
+                    return false;
+                }
+                true
+            });
+
+            for (&mode, list) in lists.iter_mut() {
+                while let Some(i) = list.iter().position(|f| f.2.elapsed() >= bot_wait) {
+                    let (player, ..) = list.remove(i);
+                    let level = player
+                        .0
+                        .tanks
+                        .iter()
+                        .find(|f| f.id == player.1)
+                        .map_or(1, |f| f.level);
+                    let trophies = player.0.trophies;
+                    let mut players = vec![player];
+                    players.extend(
+                        (1..mode.players_count()).map(|_| BalancedPlayer::bot(trophies, level)),
+                    );
+                    PHYSICS
+                        .get()
+                        .send(physics::PhysicsCommand::CreateMatch {
+                            players,
+                            mode,
+                            private: None,
+                        })
+                        .unwrap();
+                }
             }
         }
         Ok(())
     }
+
+    //Player is in a matchmaker queue or in a private lobby
+    fn is_waiting(
+        lists: &HashMap<BattleMode, Vec<(BalancedPlayer, i32, Instant)>>,
+        private: &HashMap<String, PrivateLobby>,
+        id: i64,
+    ) -> bool {
+        lists.values().flatten().any(|f| f.0 .0.id == id)
+            || private
+                .values()
+                .flat_map(|f| &f.players)
+                .any(|f| f.0.id == id)
+    }
+
+    fn invite_code() -> String {
+        let mut gen = rand::thread_rng();
+        (0..CODE_LEN)
+            .map(|_| CODE_CHARS[gen.gen_range(0..CODE_CHARS.len())] as char)
+            .collect()
+    }
+
+    //Sends the packet on a new stream without waiting for it
+    fn notify(conn: &quinn::Connection, packet: &data::Packet) {
+        let mut buf = Vec::new();
+        let mut serializer = Serializer::new(&mut buf);
+        packet.serialize(&mut serializer).unwrap();
+        let conn = conn.clone();
+        tokio::spawn(async move {
+            let mut send = conn.open_uni().await?;
+            send.write_all(&buf).await?;
+            send.finish().await?;
+            Result::<()>::Ok(())
+        });
+    }
+
     async fn handle_connection(mut conn: quinn::NewConnection) -> Result<()> {
         let span = info_span!(
             "connection",
@@ -301,7 +500,7 @@ impl Server {
                 }
                 _ => unimplemented!(),
             },
-            data::Packet::JoinMatchMakerRequest { id: tank_id } => {
+            data::Packet::JoinMatchMakerRequest { id: tank_id, mode } => {
                 let client = CLIENTS.get().get(&conn.stable_id());
                 let id = client.as_ref().map(|f| f.id);
                 let conn = conn.clone();
@@ -310,12 +509,83 @@ impl Server {
                     return Ok(enum_name);
                 }
                 let player = db::get_player_by_id(id.unwrap()).unwrap();
+                if let Some(time_left) = player.penalty_left() {
+                    let mut buf = Vec::new();
+                    let mut serializer = Serializer::new(&mut buf);
+                    data::Packet::MatchMakerPenaltyResponse { time_left }
+                        .serialize(&mut serializer)?;
+                    let mut send = conn.open_uni().await?;
+                    send.write_all(&buf).await?;
+                    send.finish().await?;
+                    return Ok(enum_name);
+                }
                 MATCHMAKER
                     .get()
                     .send(BalancerCommand::AddPlayer {
                         player: Box::new(player),
                         tank_id,
                         conn,
+                        mode,
+                    })
+                    .unwrap();
+            }
+            data::Packet::CreatePrivateMatchRequest {
+                id: tank_id,
+                mode,
+                map,
+                battle_time,
+            } => {
+                let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                if id.is_none() {
+                    error!("unauthorized access");
+                    return Ok(enum_name);
+                }
+                let error = if map
+                    .as_ref()
+                    .is_some_and(|f| !physics::private_map_exists(f, mode))
+                {
+                    Some("Unknown map")
+                } else if battle_time
+                    .is_some_and(|f| !(MIN_PRIVATE_TIME..=MAX_PRIVATE_TIME).contains(&f))
+                {
+                    Some("Wrong battle time")
+                } else {
+                    None
+                };
+                if let Some(error) = error {
+                    let packet = data::Packet::CreatePrivateMatchResponse {
+                        code: None,
+                        error: Some(error.to_owned()),
+                    };
+                    Self::notify(&conn, &packet);
+                    return Ok(enum_name);
+                }
+                let player = db::get_player_by_id(id.unwrap()).unwrap();
+                MATCHMAKER
+                    .get()
+                    .send(BalancerCommand::CreatePrivateMatch {
+                        player: Box::new(player),
+                        tank_id,
+                        conn,
+                        mode,
+                        settings: PrivateMatch { map, battle_time },
+                    })
+                    .unwrap();
+            }
+            data::Packet::JoinPrivateMatchRequest { code, id: tank_id } => {
+                let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                if id.is_none() {
+                    error!("unauthorized access");
+                    return Ok(enum_name);
+                }
+                let player = db::get_player_by_id(id.unwrap()).unwrap();
+                MATCHMAKER
+                    .get()
+                    .send(BalancerCommand::JoinPrivateMatch {
+                        player: Box::new(player),
+                        tank_id,
+                        conn,
+                        code,
                     })
                     .unwrap();
             }
@@ -336,6 +606,74 @@ impl Server {
                     PHYSICS.get().send(cmd).unwrap();
                 }
             }
+            data::Packet::UseAbility => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::PlayerAbility { id: client.id };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
+            data::Packet::RematchRequest | data::Packet::DeclineRematchRequest => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::Rematch {
+                        id: client.id,
+                        accept: matches!(packet, data::Packet::RematchRequest),
+                        conn: conn.clone(),
+                    };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
+            data::Packet::SendEmote { id: emote } => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::PlayerEmote {
+                        id: client.id,
+                        emote,
+                    };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
+            data::Packet::MutePlayer { nickname, muted } => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::PlayerMute {
+                        id: client.id,
+                        nickname,
+                        muted,
+                    };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
+            data::Packet::Surrender => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::PlayerSurrender { id: client.id };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
+            data::Packet::ReplayRequest { id: replay_id } => {
+                let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                if id.is_none() {
+                    error!("unauthorized access");
+                    return Ok(enum_name);
+                }
+                physics::play_replay(replay_id, id.unwrap(), conn);
+            }
+            data::Packet::SpectateRequest { id: target } => {
+                let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                if id.is_none() {
+                    error!("unauthorized access");
+                    return Ok(enum_name);
+                }
+                let cmd = PhysicsCommand::AddSpectator {
+                    id: id.unwrap(),
+                    target,
+                    conn,
+                };
+                PHYSICS.get().send(cmd).unwrap();
+            }
+            data::Packet::StopSpectatingRequest => {
+                if let Some(client) = CLIENTS.get().get(&conn.stable_id()) {
+                    let cmd = PhysicsCommand::RemoveSpectator { id: client.id };
+                    PHYSICS.get().send(cmd).unwrap();
+                }
+            }
             _ => {
                 error!("wrong packet came from uni stream!");
             }
@@ -536,6 +874,48 @@ impl Server {
                             send.write_all(&buf).await?;
                         }
                     }
+                    data::Packet::FriendsListRequest => {
+                        let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                        if id.is_none() {
+                            error!("unauthorized access");
+                            return Ok(enum_name);
+                        }
+                        let mut buf = Vec::new();
+                        let mut serializer = Serializer::new(&mut buf);
+                        let (friends, incoming, outgoing) = friends::list(id.unwrap())?;
+                        let packet = data::Packet::FriendsListResponse {
+                            friends,
+                            incoming,
+                            outgoing,
+                        };
+                        packet.serialize(&mut serializer)?;
+                        send.write_all(&buf).await?;
+                    }
+                    data::Packet::AddFriendRequest { ref nickname }
+                    | data::Packet::AcceptFriendRequest { ref nickname }
+                    | data::Packet::DeclineFriendRequest { ref nickname }
+                    | data::Packet::RemoveFriendRequest { ref nickname } => {
+                        let id = CLIENTS.get().get(&conn.stable_id()).map(|f| f.id);
+                        if id.is_none() {
+                            error!("unauthorized access");
+                            return Ok(enum_name);
+                        }
+                        let mut buf = Vec::new();
+                        let mut serializer = Serializer::new(&mut buf);
+                        let action = match packet {
+                            data::Packet::AddFriendRequest { .. } => friends::add,
+                            data::Packet::AcceptFriendRequest { .. } => friends::accept,
+                            data::Packet::DeclineFriendRequest { .. } => friends::decline,
+                            _ => friends::remove,
+                        };
+                        let error = action(id.unwrap(), nickname)?;
+                        let packet = data::Packet::FriendResponse {
+                            nickname: nickname.clone(),
+                            error: error.map(str::to_owned),
+                        };
+                        packet.serialize(&mut serializer)?;
+                        send.write_all(&buf).await?;
+                    }
                     data::Packet::UpgradeTankRequest { id: tank_id } => {
                         let id = CLIENTS.get().get_mut(&conn.stable_id()).map(|f| f.id);
                         if id.is_none() {
@@ -1,183 +1,94 @@
-use std::{collections::HashMap, str::FromStr};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use flume::{Sender, TryRecvError, Receiver};
+use color_eyre::eyre::{bail, WrapErr};
+use flume::{Receiver, Sender};
 use minstant::Instant;
 use quinn::Connection;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rapier2d::{na::UnitComplex, prelude::*};
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::data::{
-    BattleResult, BattleResultStruct, BulletData, GamePacket, GamePlayerData, Map, Packet, Player,
-    PlayerPosition, Tank, TankInfo, RUNTIME, TANKS,
+    ActiveEffect, BattleMode, BattleParticipant, BattlePhase, BattleResult, BattleResultStruct,
+    BulletData, GamePacket, GamePlayerData, Map, Overtime, Packet, Player, PlayerPosition,
+    PrivateMatch, SpawnPoint, Tank, TankInfo, CONFIG, MAP_VERSION, RUNTIME, TANKS,
 };
 
+mod ability;
+mod anticheat;
+mod ballistics;
+mod bot;
+mod catalogue;
+mod chat;
+mod lag;
+mod leaver;
+mod lobby;
+mod overtime;
+mod pickup;
+mod replay;
+mod rotation;
+mod scheduler;
+mod simulation;
+mod snapshot;
+mod spectator;
+mod visibility;
+
+use anticheat::InputMonitor;
+use ballistics::BulletState;
+use bot::Bot;
+pub use catalogue::validate_assets;
+use lag::Snapshot;
+use pickup::Pickup;
+pub use replay::*;
+pub use scheduler::in_battle;
+pub use simulation::*;
+use snapshot::SentState;
+use spectator::Spectator;
+use visibility::{Bush, REVEAL_TIME};
+
 type Result<T> = color_eyre::Result<T>;
 
-const UPDATE_TIME: f32 = 1f32 / 30f32;
+//Ticks per second of battles that are not configured, like simulations and replays
+const DEFAULT_TICK_RATE: u32 = 30;
+//Ticks run at once when the worker is late, the rest of the time is dropped
+const MAX_CATCH_UP_TICKS: u32 = 5;
 const WAIT_TIME: f32 = 5f32;
 const MAX_BATTLE_TIME: f32 = 60f32 * 3f32;
 const SCALE_TO_PHYSICS: f32 = 1f32 / 50f32;
 const SCALE_TO_PIXELS: f32 = 50f32;
 
-macro_rules! send_results {
-    // macth like arm for macro
-    ($x:ident, $gen:ident, $a:tt, $b:tt, $draw:expr) => {
-        // macro expand to this code
-        let acc = $x.players.$b.stats.succeeded_shots as f32 / $x.players.$b.stats.shots as f32;
-        let eff = (acc + 0.5f32)
-            * if ($x.players.$b.stats.damage_dealt as f32 / $x.players.$b.stats.damage_taken as f32)
-                .is_normal()
-            {
-                ($x.players.$b.stats.damage_dealt as f32 / $x.players.$b.stats.damage_taken as f32)
-            } else {
-                1f32
-            };
-
-        let mut win_results = BattleResultStruct {
-            result: BattleResult::Victory,
-            trophies: 30
-                + ($x.players.$a.player.get_efficiency() - $x.players.$b.player.get_efficiency())
-                    as i32,
-            xp: ((if eff.is_normal() { eff } else { 0f32 }) * 15f32) as i32,
-            coins: $gen.gen_range(70..=100)
-                + $gen.gen_range(10..=15)
-                    * ($x.players.$a.player.rank_level - $x.players.$b.player.rank_level),
-            damage_dealt: $x.players.$b.stats.damage_dealt,
-            damage_taken: $x.players.$b.stats.damage_taken,
-            accuracy: if acc.is_normal() { acc } else { 0f32 },
-            efficiency: if eff.is_normal() { eff } else { 0f32 },
-        };
-
-        let acc = $x.players.$a.stats.succeeded_shots as f32 / $x.players.$a.stats.shots as f32;
-        let eff = (acc + 0.5f32)
-            * if ($x.players.$a.stats.damage_dealt as f32 / $x.players.$a.stats.damage_taken as f32)
-                .is_normal()
-            {
-                ($x.players.$a.stats.damage_dealt as f32 / $x.players.$a.stats.damage_taken as f32)
-            } else {
-                1f32
-            };
-
-        let mut lose_results = BattleResultStruct {
-            result: BattleResult::Defeat,
-            trophies: -30
-                - ($x.players.$a.player.get_efficiency() - $x.players.$b.player.get_efficiency())
-                    as i32,
-            xp: ((if eff.is_normal() { eff } else { 0f32 }) * 15f32) as i32,
-            coins: $gen.gen_range(15..=20)
-                + $gen.gen_range(10..=15)
-                    * ($x.players.$b.player.rank_level - $x.players.$a.player.rank_level),
-            damage_dealt: $x.players.$a.stats.damage_dealt,
-            damage_taken: $x.players.$a.stats.damage_taken,
-            accuracy: if acc.is_normal() { acc } else { 0f32 },
-            efficiency: if eff.is_normal() { eff } else { 0f32 },
-        };
-
-        if $draw {
-            win_results.result = BattleResult::Draw;
-            win_results.xp = 0;
-            win_results.coins = 0;
-            win_results.trophies = 0;
-            lose_results.result = BattleResult::Draw;
-            lose_results.xp = 0;
-            lose_results.coins = 0;
-            lose_results.trophies = 0;
-        }
-        if !$draw {
-            $x.players.$b.player.victories_count += 1;
-        }
-        $x.players.$b.player.trophies += win_results.trophies;
-        $x.players.$b.player.xp += win_results.xp;
-        $x.players.$b.player.coins += win_results.coins;
-        let xp_bound = (3f32.powf($x.players.$b.player.rank_level as f32 / 10f32)
-            * $x.players.$b.player.rank_level as f32
-            * 50f32) as i32;
-        if $x.players.$b.player.xp >= xp_bound {
-            $x.players.$b.player.xp -= xp_bound;
-            $x.players.$b.player.rank_level += 1;
-        }
-        $x.players.$b.player.accuracy = ($x.players.$b.player.accuracy
-            * ($x.players.$b.player.battles_count as f32 - 1f32)
-            + win_results.accuracy)
-            / $x.players.$b.player.battles_count as f32;
-        $x.players.$b.player.damage_dealt = ($x.players.$b.player.damage_dealt
-            * $x.players.$b.player.battles_count
-            + win_results.damage_dealt)
-            / $x.players.$b.player.battles_count;
-        $x.players.$b.player.damage_taken = ($x.players.$b.player.damage_taken
-            * $x.players.$b.player.battles_count
-            + win_results.damage_taken)
-            / $x.players.$b.player.battles_count;
-
-        $x.players.$a.player.trophies =
-            0.max($x.players.$a.player.trophies + lose_results.trophies);
-        $x.players.$a.player.xp += lose_results.xp;
-        $x.players.$a.player.coins += lose_results.coins;
-        let xp_bound = (3f32.powf($x.players.$a.player.rank_level as f32 / 10f32)
-            * $x.players.$a.player.rank_level as f32
-            * 50f32) as i32;
-        if $x.players.$a.player.xp >= xp_bound {
-            $x.players.$a.player.xp -= xp_bound;
-            $x.players.$a.player.rank_level += 1;
-        }
-        $x.players.$a.player.accuracy = ($x.players.$a.player.accuracy
-            * ($x.players.$a.player.battles_count as f32 - 1f32)
-            + win_results.accuracy)
-            / $x.players.$a.player.battles_count as f32;
-        $x.players.$a.player.damage_dealt = ($x.players.$a.player.damage_dealt
-            * $x.players.$a.player.battles_count
-            + win_results.damage_dealt)
-            / $x.players.$a.player.battles_count;
-        $x.players.$a.player.damage_taken = ($x.players.$a.player.damage_taken
-            * $x.players.$a.player.battles_count
-            + win_results.damage_taken)
-            / $x.players.$a.player.battles_count;
-
-        let winner_conn = $x.players.$b.conn.clone();
-        let win_profile = (*$x.players.$b.player).clone();
-        let loser_conn = $x.players.$a.conn.clone();
-        let loser_profile = (*$x.players.$a.player).clone();
-        RUNTIME.get().spawn(async move {
-            let data = Packet::BattleResultResponse {
-                profile: win_profile,
-                result: win_results,
-            };
-            let mut buf = Vec::new();
-            let mut serializer = Serializer::new(&mut buf);
-            data.serialize(&mut serializer).unwrap();
-            let mut uni = winner_conn.open_uni().await?;
-            uni.write_all(&buf).await?;
-            uni.finish().await?;
-            let data = Packet::BattleResultResponse {
-                profile: loser_profile,
-                result: lose_results,
-            };
-            let mut buf = Vec::new();
-            let mut serializer = Serializer::new(&mut buf);
-            data.serialize(&mut serializer).unwrap();
-            let mut uni = loser_conn.open_uni().await?;
-            uni.write_all(&buf).await?;
-            uni.finish().await?;
-            Result::<()>::Ok(())
-        });
-    };
-}
+static ASSETS: state::Storage<Assets> = state::Storage::new();
 
 #[derive(Debug)]
-pub struct BalancedPlayer(pub Box<Player>, pub i32, pub Connection);
+//Participant without connection is a bot controlled by the server
+pub struct BalancedPlayer(pub Box<Player>, pub i32, pub Option<Connection>);
 
 struct WorldPlayer<'a> {
     tank_info: &'a TankInfo,
     tank: Tank,
     player: Box<Player>,
-    conn: Connection,
+    conn: Option<Connection>,
     stats: PlayerInfo,
     handle: RigidBodyHandle,
     connected: bool,
     frame: u16,
+    ack_frame: Option<u16>,
+    sent: VecDeque<SentState>,
+    //Seconds left until the tank can hide in a bush again
+    revealed: f32,
+    team: u8,
+    bot: Option<Bot>,
+    monitor: InputMonitor,
+    //Seconds since datagrams to the player started failing
+    absent: f32,
+    forfeited: bool,
+    surrendered: bool,
+    //Emotes the player can send right now, refilled over time
+    emotes: f32,
+    //Ids of players whose emotes are not relayed to this one
+    muted: Vec<i64>,
 }
 
 #[derive(Default)]
@@ -191,6 +102,9 @@ struct PlayerInfo {
     gun_rotation: f32,
     gun_angle: f32,
     cool_down: f32,
+    effect: Option<ActiveEffect>,
+    ability_time: f32,
+    ability_cool_down: f32,
 }
 
 impl TryFrom<BalancedPlayer> for WorldPlayer<'_> {
@@ -204,27 +118,81 @@ impl TryFrom<BalancedPlayer> for WorldPlayer<'_> {
             .find(|f| f.id == value.1)
             .ok_or("Wrong id!")?
             .clone();
-        Ok(Self {
-            tank_info: TANKS
-                .get()
-                .iter()
-                .find(|f| f.id as i32 == value.1)
-                .ok_or("Wrong id!")?,
-            player: value.0,
-            conn: value.2,
+        let tank_info = TANKS
+            .get()
+            .iter()
+            .find(|f| f.id as i32 == value.1)
+            .ok_or("Wrong id!")?;
+        let bot = value.2.is_none().then(|| Bot::new(value.0.trophies));
+        let mut player = Self::new(value.0, tank, tank_info, value.2);
+        player.bot = bot;
+        Ok(player)
+    }
+}
+
+impl<'a> WorldPlayer<'a> {
+    fn new(
+        player: Box<Player>,
+        tank: Tank,
+        tank_info: &'a TankInfo,
+        conn: Option<Connection>,
+    ) -> Self {
+        Self {
+            tank_info,
+            player,
+            connected: conn.is_some(),
+            conn,
             tank,
             stats: PlayerInfo::default(),
             handle: RigidBodyHandle::invalid(),
-            connected: true,
             frame: 0,
-        })
+            ack_frame: None,
+            sent: VecDeque::new(),
+            revealed: 0f32,
+            team: 0,
+            bot: None,
+            monitor: InputMonitor::default(),
+            absent: 0f32,
+            forfeited: false,
+            surrendered: false,
+            emotes: chat::EMOTE_BURST,
+            muted: Vec::new(),
+        }
+    }
+
+    fn init_stats(&mut self) {
+        self.stats.hp = self.max_hp();
+        self.stats.damage = self.base_damage();
+        self.stats.cool_down = self.tank_info.characteristics.reloading;
+        //Like the gun, ability is ready after its first cool down
+        self.stats.ability_cool_down = self
+            .tank_info
+            .characteristics
+            .ability
+            .as_ref()
+            .map_or(0f32, |f| f.cool_down);
+    }
+
+    fn level_multiplier(&self) -> f32 {
+        1f32 + (self.tank.level - 1) as f32 / 10f32
+    }
+
+    fn max_hp(&self) -> i32 {
+        (self.tank_info.characteristics.hp * self.level_multiplier()) as i32
+    }
+
+    //Damage without boosts
+    fn base_damage(&self) -> i32 {
+        (self.tank_info.characteristics.damage * self.level_multiplier()) as i32
     }
 }
 
 #[derive(Debug)]
 pub enum PhysicsCommand {
     CreateMatch {
-        players: (BalancedPlayer, BalancedPlayer),
+        players: Vec<BalancedPlayer>,
+        mode: BattleMode,
+        private: Option<PrivateMatch>,
     },
     PlayerPacket {
         id: i64,
@@ -233,21 +201,39 @@ pub enum PhysicsCommand {
     PlayerShoot {
         id: i64,
     },
+    PlayerAbility {
+        id: i64,
+    },
+    PlayerSurrender {
+        id: i64,
+    },
+    PlayerEmote {
+        id: i64,
+        emote: u16,
+    },
+    PlayerMute {
+        id: i64,
+        nickname: String,
+        muted: bool,
+    },
+    //Request or acceptance of a rematch after the battle
+    Rematch {
+        id: i64,
+        accept: bool,
+        conn: Connection,
+    },
     NotifyPlayerAboutMatch {
         id: i64,
         new_conn: Connection,
     },
-}
-
-enum ObjectConstants {
-    RedBarrage = 1,
-    YellowBarrage = 2,
-    RustyBarrelStay = 3,
-    RustyBarrelLay = 4,
-    StealHedgehog = 5,
-    WoodenHedgehog = 6,
-    LargeBush = 7,
-    SmallBush = 8,
+    AddSpectator {
+        id: i64,
+        target: Option<i64>,
+        conn: Connection,
+    },
+    RemoveSpectator {
+        id: i64,
+    },
 }
 
 #[repr(u64)]
@@ -257,6 +243,7 @@ enum BodyType {
     Tank,
     #[default]
     Other,
+    Pickup,
 }
 
 #[derive(PartialEq, Default, Debug, Clone, Copy)]
@@ -326,7 +313,7 @@ impl PhysicsHooks for CustomPhysicsHooks {
 }
 
 struct ChannelledEventCollector {
-    collision_event_sender: Sender<(CollisionEvent, Point<Real>)>,
+    collision_event_sender: Sender<(CollisionEvent, Point<Real>, Vector<Real>)>,
 }
 
 impl EventHandler for ChannelledEventCollector {
@@ -344,9 +331,11 @@ impl EventHandler for ChannelledEventCollector {
                 .find(|f| f.data.solver_contacts.get(0).is_some());
 
             if let Some(point) = point {
-                let _ = self
-                    .collision_event_sender
-                    .send((event, point.data.solver_contacts[0].point));
+                let _ = self.collision_event_sender.send((
+                    event,
+                    point.data.solver_contacts[0].point,
+                    point.data.normal,
+                ));
             }
         }
     }
@@ -387,1048 +376,198 @@ mod tests {
         let converted_data: UserData = number.into();
         assert_eq!(data, converted_data);
     }
-}
 
-pub fn start() -> Sender<PhysicsCommand> {
-    let mut object_sizes = HashMap::new();
-    object_sizes.insert(ObjectConstants::RedBarrage as i32, point![96.0, 32.0]);
-    object_sizes.insert(ObjectConstants::YellowBarrage as i32, point![104.0, 32.0]);
-    object_sizes.insert(ObjectConstants::RustyBarrelStay as i32, point![48.0, 48.0]);
-    object_sizes.insert(ObjectConstants::RustyBarrelLay as i32, point![40.0, 56.0]);
-    object_sizes.insert(ObjectConstants::StealHedgehog as i32, point![56.0, 56.0]);
-    object_sizes.insert(ObjectConstants::WoodenHedgehog as i32, point![56.0, 56.0]);
-    object_sizes.insert(ObjectConstants::LargeBush as i32, point![128.0, 128.0]);
-    object_sizes.insert(ObjectConstants::SmallBush as i32, point![72.0, 72.0]);
-
-    let mut bullet_sizes = HashMap::new();
-    bullet_sizes.insert("1 (4)", vector![16.0, 28.0]);
-    bullet_sizes.insert("4", vector![16.0, 52.0]);
-    bullet_sizes.insert("2 (2)", vector![16.0, 36.0]);
-    bullet_sizes.insert("2 (3)", vector![13.0, 29.0]);
-    bullet_sizes.insert("3", vector![24.0, 32.0]);
-
-    let mut gun_sizes = HashMap::new();
-    gun_sizes.insert("1 (4)", vector![24.0, 60.0]);
-    gun_sizes.insert("5", vector![28.0, 72.0]);
-    gun_sizes.insert("2 (2)", vector![24.0, 60.0]);
-    gun_sizes.insert("4", vector![28.0, 64.0]);
-    gun_sizes.insert("3", vector![32.0, 60.0]);
-    gun_sizes.insert("9", vector![36.0, 80.0]);
-
-    let maps = load_maps("Maps").unwrap();
-    let mut data = std::fs::read_to_string("Maps/MapObjects/MapObjects.polygons").unwrap();
-    let map_objects = BodyEditorLoader::from_json(&data).unwrap();
-    data = std::fs::read_to_string("Tanks/TanksBodies.polygons").unwrap();
-    let bodies = BodyEditorLoader::from_json(&data).unwrap();
-    data = std::fs::read_to_string("Tanks/Bullets.polygons").unwrap();
-    let bullets = BodyEditorLoader::from_json(&data).unwrap();
-    drop(data);
-    let (send, recv) = flume::unbounded();
-
-    let mut map = HashMap::new();
-    std::thread::spawn(move || {
-        let mut battles = Vec::new();
-        let mut gen = rand::thread_rng();
-        loop {
-            for i in 0..battles.len() + 1 {
-                match recv.try_recv() {
-                    Ok(cmd) => match cmd {
-                        PhysicsCommand::CreateMatch {
-                            players: (player1, player2),
-                        } => {
-                            if !map.contains_key(&player1.0.id) && !map.contains_key(&player2.0.id)
-                            {
-                                if let (
-                                    Ok::<WorldPlayer, _>(mut player1),
-                                    Ok::<WorldPlayer, _>(mut player2),
-                                ) = (player1.try_into(), player2.try_into())
-                                {
-                                    let (collision_send, collision_recv) = flume::unbounded();
-                                    let event_handler = ChannelledEventCollector {
-                                        collision_event_sender: collision_send,
-                                    };
-                                    let mut world = PhysicsWorld {
-                                        bodies: RigidBodySet::new(),
-                                        colliders: ColliderSet::new(),
-                                        gravity: vector![0.0, 0.0],
-                                        integration_parameters: IntegrationParameters::default(),
-                                        physics_pipeline: PhysicsPipeline::new(),
-                                        islands: IslandManager::new(),
-                                        broad_phase: BroadPhase::new(),
-                                        narrow_phase: NarrowPhase::new(),
-                                        impulse_joints: ImpulseJointSet::new(),
-                                        multibody_joints: MultibodyJointSet::new(),
-                                        ccd_solver: CCDSolver::new(),
-                                        hooks: Box::new(CustomPhysicsHooks),
-                                        events: Box::new(event_handler),
-                                    };
-                                    //add physics objects
-                                    let battle_map = &maps[gen.gen_range(0..maps.len())];
-                                    attach_box(
-                                        &mut world.bodies,
-                                        &mut world.colliders,
-                                        battle_map.width as f32 * SCALE_TO_PHYSICS,
-                                        battle_map.height as f32 * SCALE_TO_PHYSICS,
-                                    );
-                                    for object in &battle_map.objects {
-                                        //if body not exists it is not material (bushes, etc...)
-                                        let name = object.id.to_string();
-                                        if map_objects.body_exists(&name) {
-                                            let mut position = Isometry::new(
-                                                vector![
-                                                    (object.x + object_sizes[&object.id].x / 2f32)
-                                                        * SCALE_TO_PHYSICS,
-                                                    (object.y + object_sizes[&object.id].y / 2f32)
-                                                        * SCALE_TO_PHYSICS
-                                                ],
-                                                0.0,
-                                            );
-                                            position.append_rotation_wrt_center_mut(
-                                                &UnitComplex::new(object.rotation.to_radians()),
-                                            );
-
-                                            let rigid_body = RigidBodyBuilder::fixed()
-                                                .user_data(
-                                                    UserData::new(BodyType::Other, 0i64).into(),
-                                                )
-                                                .position(position)
-                                                .build();
-
-                                            let collider = map_objects.create_collider(
-                                                &name,
-                                                object_sizes.get(&object.id).unwrap().x as f32
-                                                    * object.scale
-                                                    * SCALE_TO_PHYSICS,
-                                            );
-
-                                            let rigid_body_handle = world.bodies.insert(rigid_body);
-                                            let handle = world.colliders.insert_with_parent(
-                                                collider,
-                                                rigid_body_handle,
-                                                &mut world.bodies,
-                                            );
-
-                                            world
-                                                .colliders
-                                                .get_mut(handle)
-                                                .unwrap()
-                                                .set_position_wrt_parent(Isometry::new(
-                                                    -object_sizes
-                                                        .get(&object.id)
-                                                        .unwrap()
-                                                        .coords
-                                                        .scale(SCALE_TO_PHYSICS)
-                                                        / 2f32,
-                                                    0.0,
-                                                ));
-                                        }
-                                    }
-                                    //add players
-                                    let mut position = Isometry::new(
-                                        vector![
-                                            battle_map.width as f32 * SCALE_TO_PHYSICS / 2f32,
-                                            battle_map.player1_y as f32 * SCALE_TO_PHYSICS
-                                        ],
-                                        0.0,
-                                    );
-                                    position.append_rotation_wrt_center_mut(&UnitComplex::new(
-                                        0f32.to_radians(),
-                                    ));
-                                    let player1_body = RigidBodyBuilder::dynamic()
-                                        .position(position)
-                                        .ccd_enabled(true)
-                                        .user_data(
-                                            UserData::new(BodyType::Tank, player1.player.id).into(),
-                                        )
-                                        .build();
-
-                                    let player1_collider = bodies.create_collider(
-                                        &player1.tank.id.to_string(),
-                                        player1.tank_info.graphics_info.tank_width as f32
-                                            * SCALE_TO_PHYSICS,
-                                    );
-                                    let player1_body_handle = world.bodies.insert(player1_body);
-                                    player1.handle = player1_body_handle;
-                                    let handle = world.colliders.insert_with_parent(
-                                        player1_collider,
-                                        player1_body_handle,
-                                        &mut world.bodies,
-                                    );
-                                    world
-                                        .colliders
-                                        .get_mut(handle)
-                                        .unwrap()
-                                        .set_position_wrt_parent(Isometry::new(
-                                            -vector![
-                                                player1.tank_info.graphics_info.tank_width as f32,
-                                                player1.tank_info.graphics_info.tank_height as f32
-                                            ] / 2f32
-                                                * SCALE_TO_PHYSICS,
-                                            0.0,
-                                        ));
-
-                                    let mut position = Isometry::new(
-                                        vector![
-                                            battle_map.width as f32 * SCALE_TO_PHYSICS / 2f32,
-                                            battle_map.player2_y as f32 * SCALE_TO_PHYSICS
-                                        ],
-                                        0.0,
-                                    );
-                                    position.append_rotation_wrt_center_mut(&UnitComplex::new(
-                                        180f32.to_radians(),
-                                    ));
-
-                                    let player2_body = RigidBodyBuilder::dynamic()
-                                        .position(position)
-                                        .ccd_enabled(true)
-                                        .user_data(
-                                            UserData::new(BodyType::Tank, player2.player.id).into(),
-                                        )
-                                        .build();
-
-                                    let player2_collider = bodies.create_collider(
-                                        &player2.tank.id.to_string(),
-                                        player2.tank_info.graphics_info.tank_width as f32
-                                            * SCALE_TO_PHYSICS,
-                                    );
-                                    let player2_body_handle = world.bodies.insert(player2_body);
-                                    player2.handle = player2_body_handle;
-                                    let handle = world.colliders.insert_with_parent(
-                                        player2_collider,
-                                        player2_body_handle,
-                                        &mut world.bodies,
-                                    );
-                                    world
-                                        .colliders
-                                        .get_mut(handle)
-                                        .unwrap()
-                                        .set_position_wrt_parent(Isometry::new(
-                                            -vector![
-                                                player2.tank_info.graphics_info.tank_width as f32,
-                                                player2.tank_info.graphics_info.tank_height as f32
-                                            ] / 2f32
-                                                * SCALE_TO_PHYSICS,
-                                            0.0,
-                                        ));
-
-                                    player1.stats.hp = (player1.tank_info.characteristics.hp as f32
-                                        * (1f32 + (player1.tank.level - 1) as f32 / 10f32))
-                                        as i32;
-                                    player2.stats.hp = (player2.tank_info.characteristics.hp as f32
-                                        * (1f32 + (player2.tank.level - 1) as f32 / 10f32))
-                                        as i32;
-                                    player1.stats.damage =
-                                        (player1.tank_info.characteristics.damage
-                                            * (1f32 + (player1.tank.level - 1) as f32 / 10f32))
-                                            as i32;
-                                    player2.stats.damage =
-                                        (player2.tank_info.characteristics.damage
-                                            * (1f32 + (player2.tank.level - 1) as f32 / 10f32))
-                                            as i32;
-                                    player1.stats.cool_down =
-                                        player1.tank_info.characteristics.reloading;
-                                    player2.stats.cool_down =
-                                        player2.tank_info.characteristics.reloading;
-
-                                    //notify players
-                                    //player1
-                                    let game_packet = GamePacket {
-                                        time_left: (MAX_BATTLE_TIME + WAIT_TIME) as u16,
-                                        my_data: GamePlayerData {
-                                            x: (battle_map.width / 2) as f32,
-                                            y: battle_map.player1_y as f32,
-                                            body_rotation: world
-                                                .bodies
-                                                .get(player2_body_handle)
-                                                .unwrap()
-                                                .position()
-                                                .rotation
-                                                .angle()
-                                                .to_degrees(),
-                                            gun_rotation: 0f32,
-                                            hp: player1.stats.hp as u16,
-                                            cool_down: player1.tank_info.characteristics.reloading,
-                                            bullets: Vec::new(),
-                                        },
-                                        opponent_data: GamePlayerData {
-                                            x: (battle_map.width / 2) as f32,
-                                            y: battle_map.player2_y as f32,
-                                            body_rotation: world
-                                                .bodies
-                                                .get(player1_body_handle)
-                                                .unwrap()
-                                                .position()
-                                                .rotation
-                                                .angle()
-                                                .to_degrees(),
-                                            gun_rotation: 180f32,
-                                            hp: player2.stats.hp as u16,
-                                            cool_down: player2.tank_info.characteristics.reloading,
-                                            bullets: Vec::new(),
-                                        },
-                                        frame_num: 0u16,
-                                    };
-                                    let data = Packet::MapFoundResponse {
-                                        wait_time: WAIT_TIME,
-                                        map: battle_map.clone(),
-                                        opponent_nick: player2.player.nickname.clone().unwrap(),
-                                        opponent_tank: player2.tank.clone(),
-                                        my_tank: player1.tank.clone(),
-                                        initial_packet: game_packet,
-                                    };
-                                    let mut buf = Vec::new();
-                                    let mut serializer = Serializer::new(&mut buf);
-                                    data.serialize(&mut serializer).unwrap();
-                                    let conn = player1.conn.clone();
-                                    RUNTIME.get().spawn(async move {
-                                        let mut uni = conn.open_uni().await?;
-                                        uni.write_all(&buf).await?;
-                                        uni.finish().await?;
-                                        Result::<()>::Ok(())
-                                    });
-
-                                    //player2
-                                    let game_packet = GamePacket {
-                                        time_left: (MAX_BATTLE_TIME + WAIT_TIME) as u16,
-                                        opponent_data: GamePlayerData {
-                                            x: (battle_map.width / 2) as f32,
-                                            y: battle_map.player1_y as f32,
-                                            body_rotation: world
-                                                .bodies
-                                                .get(player2_body_handle)
-                                                .unwrap()
-                                                .position()
-                                                .rotation
-                                                .angle()
-                                                .to_degrees(),
-                                            gun_rotation: 0f32,
-                                            hp: player1.stats.hp as u16,
-                                            cool_down: player1.tank_info.characteristics.reloading,
-                                            bullets: Vec::new(),
-                                        },
-                                        my_data: GamePlayerData {
-                                            x: (battle_map.width / 2) as f32,
-                                            y: battle_map.player2_y as f32,
-                                            body_rotation: world
-                                                .bodies
-                                                .get(player1_body_handle)
-                                                .unwrap()
-                                                .position()
-                                                .rotation
-                                                .angle()
-                                                .to_degrees(),
-                                            gun_rotation: 180f32,
-                                            hp: player2.stats.hp as u16,
-                                            cool_down: player2.tank_info.characteristics.reloading,
-                                            bullets: Vec::new(),
-                                        },
-                                        frame_num: 0u16,
-                                    };
-                                    let data = Packet::MapFoundResponse {
-                                        wait_time: WAIT_TIME,
-                                        map: battle_map.clone(),
-                                        opponent_nick: player1.player.nickname.clone().unwrap(),
-                                        opponent_tank: player1.tank.clone(),
-                                        my_tank: player2.tank.clone(),
-                                        initial_packet: game_packet,
-                                    };
-                                    let mut buf = Vec::new();
-                                    let mut serializer = Serializer::new(&mut buf);
-                                    data.serialize(&mut serializer).unwrap();
-                                    let conn = player2.conn.clone();
-                                    RUNTIME.get().spawn(async move {
-                                        let mut uni = conn.open_uni().await?;
-                                        uni.write_all(&buf).await?;
-                                        uni.finish().await?;
-                                        Result::<()>::Ok(())
-                                    });
-
-                                    let battle = Battle {
-                                        world,
-                                        map: battle_map,
-                                        players: (player1, player2),
-                                        step: Instant::now(),
-                                        time: MAX_BATTLE_TIME + WAIT_TIME,
-                                        collision_recv,
-                                        frame: 0u16,
-                                    };
-
-                                    map.insert(battle.players.0.player.id, battles.len());
-                                    map.insert(battle.players.1.player.id, battles.len());
-                                    battles.push(battle);
-                                }
-                            }
-                        }
-                        PhysicsCommand::PlayerPacket { id, position } => {
-                            if let Some(&index) = map.get(&id) {
-                                let battle = &mut battles[index];
-                                let player = if id == battle.players.0.player.id {
-                                    &mut battle.players.0
-                                } else {
-                                    &mut battle.players.1
-                                };
-                                if player.frame < position.frame_num {
-                                    player.frame = position.frame_num;
-                                    if battle.time <= MAX_BATTLE_TIME {
-                                        //Body rotation
-                                        let player_body =
-                                            battle.world.bodies.get_mut(player.handle).unwrap();
-                                        let back_angle =
-                                            revert_angle_by_y(player_body.rotation().angle());
-
-                                        let mut diff = 0f32;
-                                        if position.body_rotation != 0f32 {
-                                            diff = position.body_rotation
-                                                - player_body.rotation().angle();
-                                            diff = diff.rem_euclid(360f32.to_radians())
-                                                - 180f32.to_radians();
-                                        }
-                                        let mut back_diff = 0f32;
-                                        if position.body_rotation != 0f32 {
-                                            back_diff = position.body_rotation - back_angle;
-                                            back_diff = back_diff.rem_euclid(360f32.to_radians())
-                                                - 180f32.to_radians();
-                                        }
-                                        let ang_vel = player
-                                            .tank_info
-                                            .characteristics
-                                            .body_rotate_degrees
-                                            .to_radians();
-
-                                        player_body.set_angvel(0f32, true);
-                                        if position.body_rotation != 0f32 {
-                                            if diff.abs() < back_diff.abs() {
-                                                let alpha = player_body.rotation().angle();
-                                                let direction = direction_by_2_angles(
-                                                    alpha,
-                                                    position.body_rotation,
-                                                );
-                                                if diff.abs() <= ang_vel * UPDATE_TIME {
-                                                    player_body.set_angvel(0f32, true);
-                                                } else {
-                                                    player_body
-                                                        .set_angvel(direction * ang_vel, true);
-                                                }
-                                            } else {
-                                                let direction = direction_by_2_angles(
-                                                    back_angle,
-                                                    position.body_rotation,
-                                                );
-                                                if back_diff.abs() <= ang_vel * UPDATE_TIME {
-                                                    player_body.set_angvel(0f32, true);
-                                                } else {
-                                                    player_body
-                                                        .set_angvel(direction * ang_vel, true);
-                                                }
-                                            }
-                                        }
-                                        if position.moving {
-                                            if diff.abs() > back_diff.abs() {
-                                                let velocity = vector![
-                                                    player.tank_info.characteristics.velocity
-                                                        * SCALE_TO_PHYSICS
-                                                        * (player_body.rotation().angle()
-                                                            - 90f32.to_radians())
-                                                        .cos(),
-                                                    player.tank_info.characteristics.velocity
-                                                        * SCALE_TO_PHYSICS
-                                                        * (player_body.rotation().angle()
-                                                            - 90f32.to_radians())
-                                                        .sin()
-                                                ];
-                                                player_body.set_linvel(velocity, true);
-                                            } else {
-                                                let velocity = vector![
-                                                    player.tank_info.characteristics.velocity
-                                                        * 0.5f32
-                                                        * SCALE_TO_PHYSICS
-                                                        * (back_angle - 90f32.to_radians()).cos(),
-                                                    player.tank_info.characteristics.velocity
-                                                        * 0.5f32
-                                                        * SCALE_TO_PHYSICS
-                                                        * (back_angle - 90f32.to_radians()).sin()
-                                                ];
-                                                player_body.set_linvel(velocity, true);
-                                            }
-                                        } else {
-                                            player_body.set_linvel(Vector::zeros(), true);
-                                        }
-
-                                        //Gun rotation
-                                        player.stats.gun_rotation = position.gun_rotation;
-                                    }
-                                }
-                            }
-                        }
-                        PhysicsCommand::PlayerShoot { id } => {
-                            if let Some(&index) = map.get(&id) {
-                                let battle = &mut battles[index];
-                                let player = if id == battle.players.0.player.id {
-                                    &mut battle.players.0
-                                } else {
-                                    &mut battle.players.1
-                                };
-                                if player.stats.cool_down == 0f32 && battle.time <= MAX_BATTLE_TIME
-                                {
-                                    player.stats.shots += 1;
-                                    player.stats.cool_down =
-                                        player.tank_info.characteristics.reloading;
-
-                                    let mut point = *battle
-                                        .world
-                                        .bodies
-                                        .get(player.handle)
-                                        .unwrap()
-                                        .translation();
-                                    let gun_angle = player.stats.gun_angle
-                                        + battle
-                                            .world
-                                            .bodies
-                                            .get(player.handle)
-                                            .unwrap()
-                                            .rotation()
-                                            .angle();
-
-                                    point -= vector![
-                                        player.tank_info.graphics_info.tank_width as f32,
-                                        -(player.tank_info.graphics_info.tank_height as f32)
-                                    ] / 2f32
-                                        * SCALE_TO_PHYSICS;
-                                    let size = gun_sizes
-                                        .get(player.tank_info.graphics_info.tank_gun_name.as_str())
-                                        .unwrap();
-                                    let rotation_point = point
-                                        + vector![
-                                            (player.tank_info.graphics_info.gun_x
-                                                + player.tank_info.graphics_info.gun_origin_x)
-                                                as f32,
-                                            -((player.tank_info.graphics_info.gun_y
-                                                + player.tank_info.graphics_info.gun_origin_y)
-                                                as f32)
-                                        ] * SCALE_TO_PHYSICS;
-                                    point += vector![
-                                        player.tank_info.graphics_info.gun_x as f32 + size.x / 2f32,
-                                        -(player.tank_info.graphics_info.gun_y as f32) - size.y
-                                    ] * SCALE_TO_PHYSICS;
-                                    let new_x = (point.x - rotation_point.x) * gun_angle.cos()
-                                        - (point.y - rotation_point.y) * gun_angle.sin()
-                                        + rotation_point.x;
-                                    let new_y = (point.x - rotation_point.x) * gun_angle.sin()
-                                        + (point.y - rotation_point.y) * gun_angle.cos()
-                                        + rotation_point.y;
-                                    point = vector![new_x, new_y];
-                                    let mut position = Isometry::new(point, 0.0);
-                                    position.append_rotation_wrt_center_mut(&UnitComplex::new(
-                                        gun_angle,
-                                    ));
-                                    let velocity = vector![
-                                        player.tank_info.characteristics.bullet_speed
-                                            * SCALE_TO_PHYSICS
-                                            * (position.rotation.angle() - 90f32.to_radians())
-                                                .cos(),
-                                        player.tank_info.characteristics.bullet_speed
-                                            * SCALE_TO_PHYSICS
-                                            * (position.rotation.angle() - 90f32.to_radians())
-                                                .sin()
-                                    ];
-
-                                    let bullet_body = RigidBodyBuilder::dynamic()
-                                        .position(position)
-                                        .linvel(velocity)
-                                        .ccd_enabled(true)
-                                        .user_data(
-                                            UserData::new(BodyType::Bullet, player.player.id)
-                                                .into(),
-                                        )
-                                        .build();
-
-                                    let bullet_collider = bullets.create_collider(
-                                        &player.tank_info.graphics_info.bullet_name,
-                                        bullet_sizes
-                                            .get(
-                                                player.tank_info.graphics_info.bullet_name.as_str(),
-                                            )
-                                            .unwrap()
-                                            .x as f32
-                                            * SCALE_TO_PHYSICS,
-                                    );
-                                    let bullet_body_handle =
-                                        battle.world.bodies.insert(bullet_body);
-                                    let handle = battle.world.colliders.insert_with_parent(
-                                        bullet_collider,
-                                        bullet_body_handle,
-                                        &mut battle.world.bodies,
-                                    );
-                                    battle
-                                        .world
-                                        .colliders
-                                        .get_mut(handle)
-                                        .unwrap()
-                                        .set_position_wrt_parent(Isometry::new(
-                                            -bullet_sizes
-                                                .get(
-                                                    player
-                                                        .tank_info
-                                                        .graphics_info
-                                                        .bullet_name
-                                                        .as_str(),
-                                                )
-                                                .unwrap()
-                                                / 2f32
-                                                * SCALE_TO_PHYSICS,
-                                            0.0,
-                                        ));
-                                }
-                            }
-                        }
-                        PhysicsCommand::NotifyPlayerAboutMatch { id, new_conn } => {
-                            if let Some(&index) = map.get(&id) {
-                                let battle = &mut battles[index];
-                                let player = if id == battle.players.0.player.id {
-                                    battle.players.0.conn = new_conn;
-                                    battle.players.0.connected = true;
-                                    &battle.players.0
-                                } else {
-                                    battle.players.1.conn = new_conn;
-                                    battle.players.1.connected = true;
-                                    &battle.players.1
-                                };
-                                let op_player = if id != battle.players.0.player.id {
-                                    &battle.players.0
-                                } else {
-                                    &battle.players.1
-                                };
-
-                                let my_pos =
-                                    battle.world.bodies.get(player.handle).unwrap().position();
-                                let op_pos = battle
-                                    .world
-                                    .bodies
-                                    .get(op_player.handle)
-                                    .unwrap()
-                                    .position();
-
-                                let game_packet = GamePacket {
-                                    time_left: battle.time as u16,
-                                    my_data: GamePlayerData {
-                                        x: my_pos.translation.x * SCALE_TO_PIXELS,
-                                        y: my_pos.translation.y * SCALE_TO_PIXELS,
-                                        body_rotation: my_pos.rotation.angle().to_degrees(),
-                                        gun_rotation: player.stats.gun_angle.to_degrees(),
-                                        hp: player.stats.hp as u16,
-                                        cool_down: player.stats.cool_down,
-                                        bullets: Vec::new(),
-                                    },
-                                    opponent_data: GamePlayerData {
-                                        x: op_pos.translation.x * SCALE_TO_PIXELS,
-                                        y: op_pos.translation.y * SCALE_TO_PIXELS,
-                                        body_rotation: op_pos.rotation.angle().to_degrees(),
-                                        gun_rotation: op_player.stats.gun_angle.to_degrees(),
-                                        hp: op_player.stats.hp as u16,
-                                        cool_down: 0f32,
-                                        bullets: Vec::new(),
-                                    },
-                                    frame_num: battle.frame,
-                                };
-                                let data = Packet::MapFoundResponse {
-                                    wait_time: 0f32.max(battle.time - MAX_BATTLE_TIME),
-                                    map: battle.map.clone(),
-                                    opponent_nick: op_player.player.nickname.clone().unwrap(),
-                                    opponent_tank: op_player.tank.clone(),
-                                    my_tank: player.tank.clone(),
-                                    initial_packet: game_packet,
-                                };
-                                let mut buf = Vec::new();
-                                let mut serializer = Serializer::new(&mut buf);
-                                data.serialize(&mut serializer).unwrap();
-                                let conn = player.conn.clone();
-                                RUNTIME.get().spawn(async move {
-                                    let mut uni = conn.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    Result::<()>::Ok(())
-                                });
-                            } else {
-                                let mut buf = Vec::new();
-                                let mut serializer = Serializer::new(&mut buf);
-                                Packet::MapNotFoundResponse.serialize(&mut serializer).unwrap();
-                                let conn = new_conn.clone();
-                                RUNTIME.get().spawn(async move {
-                                    let mut uni = conn.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    Result::<()>::Ok(())
-                                });
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        if e == TryRecvError::Disconnected {
-                            break;
-                        }
-                    }
-                }
-                if i >= battles.len() {
-                    continue;
-                }
-                let step = battles[i].step.elapsed().as_secs_f32();
-                if step >= UPDATE_TIME {
-                    battles[i].step = Instant::now();
-                    battles[i].time -= step;
-
-                    //End battle
-                    if battles[i].time <= 0f32
-                        || battles[i].players.0.stats.hp == 0
-                        || battles[i].players.1.stats.hp == 0
-                    {
-                        battles[i].players.0.player.battles_count += 1;
-                        battles[i].players.1.player.battles_count += 1;
-
-                        if battles[i].players.0.stats.hp == 0 {
-                            let battle = &mut battles[i];
-                            send_results!(battle, gen, 0, 1, false);
-                        } else if battles[i].players.1.stats.hp == 0 {
-                            let battle = &mut battles[i];
-                            send_results!(battle, gen, 1, 0, false);
-                        } else {
-                            let battle = &mut battles[i];
-                            send_results!(battle, gen, 1, 0, true);
-                        }
+    #[test]
+    fn test_malformed_body_is_reported() {
+        let body = |polygon: &str| {
+            let json = format!(
+                r#"{{"rigidBodies": [{{"name": "1", "imagePath": "1.png",
+                "origin": {{"x": 0, "y": 0}}, "polygons": [{}], "circles": []}}]}}"#,
+                polygon
+            );
+            BodyEditorLoader::from_json(&json).map_err(|e| format!("{:#}", e))
+        };
+        assert!(body(r#"[{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": 0, "y": 1}]"#).is_ok());
+        assert_eq!(
+            body(r#"[{"x": 0, "y": 0}, {"x": 1}]"#).unwrap_err(),
+            "body 1: polygon 0: y is not a number"
+        );
+        assert_eq!(
+            body(r#"[{"x": 0, "y": 0}, {"x": 1, "y": 1}]"#).unwrap_err(),
+            "body 1: polygon 0: no convex hull"
+        );
+    }
 
-                        map.remove(&battles[i].players.0.player.id);
-                        map.remove(&battles[i].players.1.player.id);
+    #[test]
+    fn test_spawn_points_replace_rows() {
+        let assets = Assets::load().unwrap();
+        let tanks = load_tanks("Tanks").unwrap();
+        let old = &assets.maps[0];
+        assert_eq!(old.version, 1);
+        let map: Map = serde_json::from_value(serde_json::json!({
+            "name": "Spawns",
+            "width": old.width,
+            "height": old.height,
+            "objects": [],
+            "version": 2,
+            "spawnPoints": [
+                {"x": 300, "y": 400, "rotation": 90, "team": 1},
+                {"x": 200, "y": 1500, "rotation": 45, "team": 0},
+            ],
+        }))
+        .unwrap();
+        let players = |count: i64| {
+            (0..count)
+                .map(|f| {
+                    let tank = Tank {
+                        id: tanks[0].id as i32,
+                        level: 1,
+                        count: 0,
+                    };
+                    let player = Player::unregistered(f + 1, String::new());
+                    WorldPlayer::new(Box::new(player), tank, &tanks[0], None)
+                })
+                .collect()
+        };
+        let battle = Battle::new(players(2), BattleMode::Duel, &map, &assets, 0);
+        assert_eq!(battle.spawn_point(0), (200f32, 1500f32, 45f32));
+        assert_eq!(battle.spawn_point(1), (300f32, 400f32, 90f32));
+        //Free-for-all needs more points than the map has, so tanks are put in rows
+        let battle = Battle::new(players(4), BattleMode::FreeForAll, &map, &assets, 0);
+        assert_eq!(battle.spawn_point(1).2, 180f32);
+    }
 
-                        if battles.len() - 1 != i {
-                            *map.get_mut(&battles[battles.len() - 1].players.0.player.id)
-                                .unwrap() = i;
-                            *map.get_mut(&battles[battles.len() - 1].players.1.player.id)
-                                .unwrap() = i;
-                        }
-                        let battle = battles.swap_remove(i);
-                        RUNTIME.get().spawn_blocking(move || {
-                            crate::db::update_player(&battle.players.0.player).unwrap();
-                            crate::db::update_player(&battle.players.1.player).unwrap();
-                        });
-                        continue;
-                    }
+    #[test]
+    fn test_hits_destroy_object() {
+        let fixture = simulation::Fixture::load();
+        let mut sim = fixture.duel();
+        let (map, assets) = (&fixture.assets.maps[0], &fixture.assets);
+        let index = map
+            .objects
+            .iter()
+            .position(|f| assets.object_hp.contains_key(&f.id))
+            .unwrap() as u32;
+        let (hp, handle) = sim.battle.objects_hp[&index];
+        let shooter = sim.battle.players[0].player.id;
+        let damage = sim.battle.players[0].stats.damage;
+        for _ in 0..(hp + damage - 1) / damage {
+            sim.battle.damage_object(shooter, index);
+        }
+        assert!(!sim.battle.objects_hp.contains_key(&index));
+        assert!(sim.battle.world.bodies.get(handle).is_none());
+        assert_eq!(sim.battle.destroyed_objects, vec![index]);
+    }
 
-                    if battles[i].time >= MAX_BATTLE_TIME {
-                        continue;
-                    }
-                    battles[i].world.integration_parameters.dt = step;
-                    battles[i].world.integration_parameters.min_ccd_dt = step / 100f32;
-                    battles[i].physics_step();
-                    battles[i].frame += 1;
-                    battles[i].players.0.stats.cool_down =
-                        0f32.max(battles[i].players.0.stats.cool_down - step);
-                    battles[i].players.1.stats.cool_down =
-                        0f32.max(battles[i].players.1.stats.cool_down - step);
-
-                    while let Ok((collision_event, mut point)) =
-                        battles[i].collision_recv.try_recv()
-                    {
-                        // Handle the collision event.
-                        if !collision_event.removed() && collision_event.started() {
-                            point *= SCALE_TO_PIXELS;
-
-                            let body1_handle = battles[i]
-                                .world
-                                .colliders
-                                .get(collision_event.collider1())
-                                .unwrap()
-                                .parent()
-                                .unwrap();
-                            let body2_handle = battles[i]
-                                .world
-                                .colliders
-                                .get(collision_event.collider2())
-                                .unwrap()
-                                .parent()
-                                .unwrap();
-                            let body1 = battles[i].world.bodies.get(body1_handle).unwrap();
-                            let body2 = battles[i].world.bodies.get(body2_handle).unwrap();
-                            let data1: UserData = body1.user_data.into();
-                            let data2: UserData = body2.user_data.into();
-                            if data1.body_type == BodyType::Bullet {
-                                battles[i].remove_body(body1_handle);
-                                let explosion = Packet::Explosion {
-                                    x: point.x,
-                                    y: point.y,
-                                    hit: data2.body_type == BodyType::Tank,
-                                };
-                                let mut buf = Vec::new();
-                                let mut serializer = Serializer::new(&mut buf);
-                                explosion.serialize(&mut serializer).unwrap();
-                                let conn1 = battles[i].players.0.conn.clone();
-                                let conn2 = battles[i].players.1.conn.clone();
-                                RUNTIME.get().spawn(async move {
-                                    let mut uni = conn1.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    let mut uni = conn2.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    Result::<()>::Ok(())
-                                });
-                                match data2.body_type {
-                                    BodyType::Bullet => {
-                                        battles[i].remove_body(body2_handle);
-                                    }
-                                    BodyType::Tank => {
-                                        if battles[i].players.1.player.id == data2.id {
-                                            let damage = battles[i]
-                                                .players
-                                                .0
-                                                .stats
-                                                .hp
-                                                .min(battles[i].players.1.stats.damage);
-                                            battles[i].players.0.stats.hp -= damage;
-                                            battles[i].players.0.stats.damage_taken += damage;
-                                            battles[i].players.1.stats.succeeded_shots += 1;
-                                            battles[i].players.1.stats.damage_dealt += damage;
-                                        }
-                                        if battles[i].players.0.player.id == data2.id {
-                                            let damage = battles[i]
-                                                .players
-                                                .1
-                                                .stats
-                                                .hp
-                                                .min(battles[i].players.0.stats.damage);
-                                            battles[i].players.1.stats.hp -= damage;
-                                            battles[i].players.1.stats.damage_taken += damage;
-                                            battles[i].players.0.stats.succeeded_shots += 1;
-                                            battles[i].players.0.stats.damage_dealt += damage;
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            if data2.body_type == BodyType::Bullet {
-                                battles[i].remove_body(body2_handle);
-                                let explosion = Packet::Explosion {
-                                    x: point.x,
-                                    y: point.y,
-                                    hit: data1.body_type == BodyType::Tank,
-                                };
-                                let mut buf = Vec::new();
-                                let mut serializer = Serializer::new(&mut buf);
-                                explosion.serialize(&mut serializer).unwrap();
-                                let conn1 = battles[i].players.0.conn.clone();
-                                let conn2 = battles[i].players.1.conn.clone();
-                                RUNTIME.get().spawn(async move {
-                                    let mut uni = conn1.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    let mut uni = conn2.open_uni().await?;
-                                    uni.write_all(&buf).await?;
-                                    uni.finish().await?;
-                                    Result::<()>::Ok(())
-                                });
-
-                                match data1.body_type {
-                                    BodyType::Bullet => {
-                                        battles[i].remove_body(body1_handle);
-                                    }
-                                    BodyType::Tank => {
-                                        if battles[i].players.1.player.id == data2.id {
-                                            let damage = battles[i]
-                                                .players
-                                                .0
-                                                .stats
-                                                .hp
-                                                .min(battles[i].players.1.stats.damage);
-                                            battles[i].players.0.stats.hp -= damage;
-                                            battles[i].players.0.stats.damage_taken += damage;
-                                            battles[i].players.1.stats.succeeded_shots += 1;
-                                            battles[i].players.1.stats.damage_dealt += damage;
-                                        }
-                                        if battles[i].players.0.player.id == data2.id {
-                                            let damage = battles[i]
-                                                .players
-                                                .1
-                                                .stats
-                                                .hp
-                                                .min(battles[i].players.0.stats.damage);
-                                            battles[i].players.1.stats.hp -= damage;
-                                            battles[i].players.1.stats.damage_taken += damage;
-                                            battles[i].players.0.stats.succeeded_shots += 1;
-                                            battles[i].players.0.stats.damage_dealt += damage;
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                    //notify players
-                    //player1
-                    let mut diff = 0f32;
-                    let gun_angle = battles[i].players.0.stats.gun_angle
-                        + battles[i]
-                            .world
-                            .bodies
-                            .get(battles[i].players.0.handle)
-                            .unwrap()
-                            .rotation()
-                            .angle();
-                    if battles[i].players.0.stats.gun_rotation != 0f32 {
-                        diff = battles[i].players.0.stats.gun_rotation - gun_angle;
-                        diff += 180f32.to_radians();
-                        diff = diff.rem_euclid(360f32.to_radians()) - 180f32.to_radians();
-                    }
-                    let ang_vel = battles[i]
-                        .players
-                        .0
-                        .tank_info
-                        .characteristics
-                        .gun_rotate_degrees
-                        .to_radians();
-
-                    if battles[i].players.0.stats.gun_rotation != 0f32 {
-                        let direction = diff.signum();
-                        if diff.abs() >= ang_vel * step {
-                            battles[i].players.0.stats.gun_angle += direction * ang_vel * step;
-                        }
-                    }
+    #[test]
+    fn test_private_battle_gives_no_rewards() {
+        let fixture = simulation::Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.private = Some(PrivateMatch::default());
+        sim.battle.set_battle_time(60f32);
+        sim.battle.players[1].bot = Some(Bot::new(2000));
+        while !sim.step() {}
+        assert!(sim.tick <= (60f32 / simulation::TICK_TIME).round() as u32);
+        let results = sim.battle.results(&mut sim.gen);
+        assert_eq!(results[1].result, BattleResult::Victory);
+        for result in results {
+            assert_eq!((result.trophies, result.coins, result.xp), (0, 0, 0));
+        }
+    }
 
-                    let mut diff = 0f32;
-                    let gun_angle = battles[i].players.1.stats.gun_angle
-                        + battles[i]
-                            .world
-                            .bodies
-                            .get(battles[i].players.1.handle)
-                            .unwrap()
-                            .rotation()
-                            .angle();
-                    if battles[i].players.1.stats.gun_rotation != 0f32 {
-                        diff = battles[i].players.1.stats.gun_rotation - gun_angle;
-                        diff += 180f32.to_radians();
-                        diff = diff.rem_euclid(360f32.to_radians()) - 180f32.to_radians();
-                    }
-                    let ang_vel = battles[i]
-                        .players
-                        .1
-                        .tank_info
-                        .characteristics
-                        .gun_rotate_degrees
-                        .to_radians();
-
-                    if battles[i].players.1.stats.gun_rotation != 0f32 {
-                        let direction = diff.signum();
-                        if diff.abs() >= ang_vel * step {
-                            battles[i].players.1.stats.gun_angle += direction * ang_vel * step;
-                        }
-                    }
+    #[test]
+    fn test_private_battle_keeps_profiles() {
+        let fixture = simulation::Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.private = Some(PrivateMatch::default());
+        for player in sim.battle.players.iter_mut() {
+            player.player.trophies = 500;
+            player.player.coins = 100;
+            player.player.xp = 10;
+        }
+        sim.battle.forfeit(1);
+        sim.battle.surrender(1);
+        while !sim.step() {}
+        sim.battle.store_leavers();
+        sim.battle.send_results(&mut sim.gen);
+        for player in &sim.battle.players {
+            let profile = &player.player;
+            assert_eq!(
+                (profile.trophies, profile.coins, profile.xp),
+                (500, 100, 10)
+            );
+            assert_eq!((profile.leaves, profile.surrenders), (0, 0));
+        }
+    }
+}
 
-                    let my_pos = *battles[i]
-                        .world
-                        .bodies
-                        .get(battles[i].players.0.handle)
-                        .unwrap()
-                        .position();
-
-                    let op_pos = *battles[i]
-                        .world
-                        .bodies
-                        .get(battles[i].players.1.handle)
-                        .unwrap()
-                        .position();
-
-                    let mut player1_bullets = Vec::new();
-                    let mut player2_bullets = Vec::new();
-
-                    for rigid_body_handle in battles[i].world.islands.active_dynamic_bodies() {
-                        let rigid_body = battles[i].world.bodies.get(*rigid_body_handle).unwrap();
-                        let data: UserData = rigid_body.user_data.into();
-                        if data.body_type == BodyType::Bullet {
-                            let bullet = BulletData {
-                                x: rigid_body.translation().x * SCALE_TO_PIXELS,
-                                y: rigid_body.translation().y * SCALE_TO_PIXELS,
-                                rotation: rigid_body.rotation().angle().to_degrees(),
-                            };
-                            if data.id == battles[i].players.0.player.id {
-                                player1_bullets.push(bullet);
-                            } else if data.id == battles[i].players.1.player.id {
-                                player2_bullets.push(bullet);
-                            }
-                        }
-                    }
+pub fn start() -> Result<Sender<PhysicsCommand>> {
+    let assets = Assets::load()?;
+    let problems = assets.problems(TANKS.get());
+    if !problems.is_empty() {
+        color_eyre::eyre::bail!("invalid assets:\n{}", problems.join("\n"));
+    }
+    ASSETS.set(assets);
+    Ok(scheduler::start(CONFIG.get().workers))
+}
 
-                    let game_packet = GamePacket {
-                        time_left: battles[i].time as u16,
-                        my_data: GamePlayerData {
-                            x: my_pos.translation.x * SCALE_TO_PIXELS,
-                            y: my_pos.translation.y * SCALE_TO_PIXELS,
-                            body_rotation: my_pos.rotation.angle().to_degrees(),
-                            gun_rotation: battles[i].players.0.stats.gun_angle.to_degrees(),
-                            hp: battles[i].players.0.stats.hp as u16,
-                            cool_down: battles[i].players.0.stats.cool_down,
-                            bullets: player1_bullets.clone(),
-                        },
-                        opponent_data: GamePlayerData {
-                            x: op_pos.translation.x * SCALE_TO_PIXELS,
-                            y: op_pos.translation.y * SCALE_TO_PIXELS,
-                            body_rotation: op_pos.rotation.angle().to_degrees(),
-                            gun_rotation: battles[i].players.1.stats.gun_angle.to_degrees(),
-                            hp: battles[i].players.1.stats.hp as u16,
-                            cool_down: 0f32,
-                            bullets: player2_bullets.clone(),
-                        },
-                        frame_num: battles[i].frame,
-                    };
-                    let mut buf = Vec::new();
-                    let mut serializer = Serializer::new(&mut buf);
-                    game_packet.serialize(&mut serializer).unwrap();
-                    if battles[i]
-                        .players
-                        .0
-                        .conn
-                        .send_datagram(bytes::Bytes::from(buf))
-                        .is_err()
-                    {
-                        battles[i].players.0.connected = false;
-                    }
+//Whether a private match of the mode can be played on the map
+pub fn private_map_exists(name: &str, mode: BattleMode) -> bool {
+    rotation::find_map(&ASSETS.get().maps, name, mode).is_some()
+}
 
-                    //player2
-                    let game_packet = GamePacket {
-                        time_left: battles[i].time as u16,
-                        opponent_data: GamePlayerData {
-                            x: my_pos.translation.x * SCALE_TO_PIXELS,
-                            y: my_pos.translation.y * SCALE_TO_PIXELS,
-                            body_rotation: my_pos.rotation.angle().to_degrees(),
-                            gun_rotation: battles[i].players.0.stats.gun_angle.to_degrees(),
-                            hp: battles[i].players.0.stats.hp as u16,
-                            cool_down: 0f32,
-                            bullets: player1_bullets,
-                        },
-                        my_data: GamePlayerData {
-                            x: op_pos.translation.x * SCALE_TO_PIXELS,
-                            y: op_pos.translation.y * SCALE_TO_PIXELS,
-                            body_rotation: op_pos.rotation.angle().to_degrees(),
-                            gun_rotation: battles[i].players.1.stats.gun_angle.to_degrees(),
-                            hp: battles[i].players.1.stats.hp as u16,
-                            cool_down: battles[i].players.1.stats.cool_down,
-                            bullets: player2_bullets,
-                        },
-                        frame_num: battles[i].frame,
-                    };
-                    let mut buf = Vec::new();
-                    let mut serializer = Serializer::new(&mut buf);
-                    game_packet.serialize(&mut serializer).unwrap();
-                    if battles[i]
-                        .players
-                        .1
-                        .conn
-                        .send_datagram(bytes::Bytes::from(buf))
-                        .is_err()
-                    {
-                        battles[i].players.0.connected = false;
-                    }
-                }
-            }
-        }
+fn send_packet(conn: &Connection, packet: &Packet) {
+    let mut buf = Vec::new();
+    let mut serializer = Serializer::new(&mut buf);
+    packet.serialize(&mut serializer).unwrap();
+    let conn = conn.clone();
+    RUNTIME.get().spawn(async move {
+        let mut uni = conn.open_uni().await?;
+        uni.write_all(&buf).await?;
+        uni.finish().await?;
+        Result::<()>::Ok(())
     });
-    send
+}
+
+//Server side of a connection on the loopback, and the client it is connected to
+#[cfg(test)]
+fn loopback() -> (Connection, quinn::NewConnection) {
+    use futures::StreamExt;
+
+    if RUNTIME.try_get().is_none() {
+        RUNTIME.set(tokio::runtime::Runtime::new().unwrap());
+    }
+    RUNTIME.get().block_on(async {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&der).unwrap();
+        let server_config = quinn::ServerConfig::with_single_cert(vec![der], key).unwrap();
+        let (server, mut incoming) =
+            quinn::Endpoint::server(server_config, ([127, 0, 0, 1], 0).into()).unwrap();
+        let mut client = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+        let connecting = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap();
+        let (client, server) =
+            tokio::join!(connecting, async { incoming.next().await.unwrap().await });
+        (server.unwrap().connection, client.unwrap())
+    })
+}
+
+//Next packet the client got through `send_packet`
+#[cfg(test)]
+fn receive(client: &mut quinn::NewConnection) -> Packet {
+    use futures::StreamExt;
+
+    RUNTIME.get().block_on(async {
+        let timeout = std::time::Duration::from_secs(5);
+        let stream = tokio::time::timeout(timeout, client.uni_streams.next()).await;
+        let stream = stream.unwrap().unwrap().unwrap();
+        let buf = stream.read_to_end(usize::MAX).await.unwrap();
+        Packet::deserialize(&mut rmp_serde::Deserializer::new(buf.as_slice())).unwrap()
+    })
 }
 
 fn direction_by_2_angles(alpha: f32, mut beta: f32) -> f32 {
@@ -1480,7 +619,31 @@ fn load_maps(path: &str) -> Result<Vec<Map>> {
         while let Some(Ok(entry)) = dir.next() {
             if entry.path().is_file() && entry.path().extension().map_or(false, |v| v == "json") {
                 let content = std::fs::read(entry.path())?;
-                let value: Map = serde_json::from_slice(&content)?;
+                let value: Map = serde_json::from_slice(&content)
+                    .wrap_err_with(|| format!("failed to parse {}", entry.path().display()))?;
+                if value.version > MAP_VERSION {
+                    color_eyre::eyre::bail!(
+                        "{}: map version {} is newer than supported {}",
+                        entry.path().display(),
+                        value.version,
+                        MAP_VERSION
+                    );
+                }
+                res.push(value);
+            }
+        }
+    }
+    Ok(res)
+}
+
+pub fn load_tanks(path: &str) -> Result<Vec<TankInfo>> {
+    let mut res = Vec::new();
+    if let Ok(mut dir) = std::fs::read_dir(path) {
+        while let Some(Ok(entry)) = dir.next() {
+            if entry.path().is_file() && entry.path().extension().map_or(false, |v| v == "json") {
+                let content = std::fs::read(entry.path())?;
+                let value: TankInfo = serde_json::from_slice(&content)
+                    .wrap_err_with(|| format!("failed to parse {}", entry.path().display()))?;
                 res.push(value);
             }
         }
@@ -1488,6 +651,47 @@ fn load_maps(path: &str) -> Result<Vec<Map>> {
     Ok(res)
 }
 
+struct Assets {
+    maps: Vec<Map>,
+    map_objects: BodyEditorLoader,
+    bodies: BodyEditorLoader,
+    bullets: BodyEditorLoader,
+    object_sizes: HashMap<i32, Point<Real>>,
+    //Objects without hp can't be destroyed
+    object_hp: HashMap<i32, i32>,
+    bush_objects: HashSet<i32>,
+    bullet_sizes: HashMap<String, Vector<Real>>,
+    gun_sizes: HashMap<String, Vector<Real>>,
+    //Ids of the emotes players can send in battle
+    emotes: HashSet<u16>,
+}
+
+impl Assets {
+    fn load() -> Result<Self> {
+        let objects = catalogue::load_objects("Maps/MapObjects/MapObjects.catalogue")?;
+        let bullet_sizes = catalogue::load_sizes("Tanks/Bullets.catalogue")?;
+        let gun_sizes = catalogue::load_sizes("Tanks/Guns.catalogue")?;
+        let emotes = catalogue::load_emotes("Chat/Emotes.catalogue")?;
+
+        let maps = load_maps("Maps")?;
+        let map_objects = BodyEditorLoader::load("Maps/MapObjects/MapObjects.polygons")?;
+        let bodies = BodyEditorLoader::load("Tanks/TanksBodies.polygons")?;
+        let bullets = BodyEditorLoader::load("Tanks/Bullets.polygons")?;
+        Ok(Self {
+            maps,
+            map_objects,
+            bodies,
+            bullets,
+            object_sizes: objects.sizes,
+            object_hp: objects.hp,
+            bush_objects: objects.bushes,
+            bullet_sizes,
+            gun_sizes,
+            emotes,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct PhysicsWorld {
     gravity: Vector<Real>,
@@ -1502,6 +706,7 @@ struct PhysicsWorld {
     impulse_joints: ImpulseJointSet,
     multibody_joints: MultibodyJointSet,
     ccd_solver: CCDSolver,
+    query_pipeline: QueryPipeline,
     #[serde(skip, default = "empty_hook")]
     hooks: Box<dyn PhysicsHooks>,
     #[serde(skip, default = "empty_hook")]
@@ -1514,15 +719,292 @@ fn empty_hook() -> Box<()> {
 
 struct Battle<'a> {
     world: PhysicsWorld,
-    players: (WorldPlayer<'a>, WorldPlayer<'a>),
+    players: Vec<WorldPlayer<'a>>,
     map: &'a Map,
+    assets: &'a Assets,
+    mode: BattleMode,
+    //Wall clock time of the last `advance`
     step: Instant,
+    //Seconds of simulation per tick
+    tick_time: f32,
+    //Wall clock time not simulated yet
+    accumulator: f32,
+    //State is sent to players every `send_interval` ticks
+    send_interval: u16,
     time: f32,
     frame: u16,
-    collision_recv: Receiver<(CollisionEvent, Point<Real>)>,
+    collision_recv: Receiver<(CollisionEvent, Point<Real>, Vector<Real>)>,
+    replay: Option<Replay>,
+    spectators: Vec<Spectator>,
+    //Frames, limits how far back the battle can be rewound for lagging players
+    max_rewind: u16,
+    history: VecDeque<(u16, Snapshot)>,
+    lagged_bullets: Vec<(RigidBodyHandle, u16)>,
+    bullet_states: HashMap<RigidBodyHandle, BulletState>,
+    next_bullet_id: u32,
+    bushes: Vec<Bush>,
+    //Whether the player with the first index sees the one with the second
+    visibility: Vec<Vec<bool>>,
+    //Hp and body of destructible objects by their index in the map
+    objects_hp: HashMap<u32, (i32, RigidBodyHandle)>,
+    destroyed_objects: Vec<u32>,
+    pickups: Vec<Pickup>,
+    next_pickup_id: u32,
+    //Seconds since the last pickup spawned
+    pickup_time: f32,
+    //Everything random in the battle, seeded so replays are simulated the same way
+    seed: u64,
+    gen: StdRng,
+    //Bots don't take from `gen`, replays apply their recorded inputs instead
+    bot_gen: StdRng,
+    overtime: Overtime,
+    //Seconds of overtime
+    overtime_time: f32,
+    phase: BattlePhase,
+    //Seconds since tanks outside of the shrinking arena were damaged
+    arena_time: f32,
+    //Team that won in overtime
+    winner: Option<u8>,
+    //Seconds absent players have to reconnect, they never forfeit if 0
+    reconnect_grace: f32,
+    //Seconds of the battle without waiting for players and overtime
+    battle_time: f32,
+    //Settings chosen by the host, results don't change trophies, coins and xp
+    private: Option<PrivateMatch>,
 }
 
-impl Battle<'_> {
+impl<'a> Battle<'a> {
+    fn new(
+        mut players: Vec<WorldPlayer<'a>>,
+        mode: BattleMode,
+        map: &'a Map,
+        assets: &'a Assets,
+        seed: u64,
+    ) -> Self {
+        for (index, player) in players.iter_mut().enumerate() {
+            player.team = mode.team_of(index);
+            player.init_stats();
+        }
+        let (collision_send, collision_recv) = flume::unbounded();
+        let event_handler = ChannelledEventCollector {
+            collision_event_sender: collision_send,
+        };
+        let world = PhysicsWorld {
+            bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            gravity: vector![0.0, 0.0],
+            integration_parameters: IntegrationParameters::default(),
+            physics_pipeline: PhysicsPipeline::new(),
+            islands: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            impulse_joints: ImpulseJointSet::new(),
+            multibody_joints: MultibodyJointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            query_pipeline: QueryPipeline::new(),
+            hooks: Box::new(CustomPhysicsHooks),
+            events: Box::new(event_handler),
+        };
+        let mut battle = Self {
+            world,
+            map,
+            assets,
+            mode,
+            players,
+            step: Instant::now(),
+            tick_time: 1f32 / DEFAULT_TICK_RATE as f32,
+            accumulator: 0f32,
+            send_interval: 1,
+            time: MAX_BATTLE_TIME + WAIT_TIME,
+            collision_recv,
+            frame: 0u16,
+            replay: None,
+            spectators: Vec::new(),
+            max_rewind: 0,
+            history: VecDeque::new(),
+            lagged_bullets: Vec::new(),
+            bullet_states: HashMap::new(),
+            next_bullet_id: 0,
+            bushes: Vec::new(),
+            visibility: Vec::new(),
+            objects_hp: HashMap::new(),
+            destroyed_objects: Vec::new(),
+            pickups: Vec::new(),
+            next_pickup_id: 0,
+            pickup_time: 0f32,
+            seed,
+            gen: StdRng::seed_from_u64(seed),
+            bot_gen: StdRng::seed_from_u64(!seed),
+            overtime: Overtime::None,
+            overtime_time: 0f32,
+            phase: BattlePhase::Regular,
+            arena_time: 0f32,
+            winner: None,
+            reconnect_grace: 0f32,
+            battle_time: MAX_BATTLE_TIME,
+            private: None,
+        };
+
+        //add physics objects
+        attach_box(
+            &mut battle.world.bodies,
+            &mut battle.world.colliders,
+            map.width as f32 * SCALE_TO_PHYSICS,
+            map.height as f32 * SCALE_TO_PHYSICS,
+        );
+        for (index, object) in map.objects.iter().enumerate() {
+            //if body not exists it is not material (bushes, etc...)
+            let name = object.id.to_string();
+            if assets.map_objects.body_exists(&name) {
+                let size = assets.object_sizes[&object.id];
+                let mut position = Isometry::new(
+                    vector![
+                        (object.x + size.x / 2f32) * SCALE_TO_PHYSICS,
+                        (object.y + size.y / 2f32) * SCALE_TO_PHYSICS
+                    ],
+                    0.0,
+                );
+                position.append_rotation_wrt_center_mut(&UnitComplex::new(
+                    object.rotation.to_radians(),
+                ));
+
+                //Walls have id 0, map objects are numbered from 1
+                let rigid_body = RigidBodyBuilder::fixed()
+                    .user_data(UserData::new(BodyType::Other, index as i64 + 1).into())
+                    .position(position)
+                    .build();
+
+                let collider = assets
+                    .map_objects
+                    .create_collider(&name, size.x * object.scale * SCALE_TO_PHYSICS);
+
+                let rigid_body_handle = battle.world.bodies.insert(rigid_body);
+                if let Some(&hp) = assets.object_hp.get(&object.id) {
+                    battle
+                        .objects_hp
+                        .insert(index as u32, (hp, rigid_body_handle));
+                }
+                let handle = battle.world.colliders.insert_with_parent(
+                    collider,
+                    rigid_body_handle,
+                    &mut battle.world.bodies,
+                );
+
+                battle
+                    .world
+                    .colliders
+                    .get_mut(handle)
+                    .unwrap()
+                    .set_position_wrt_parent(Isometry::new(
+                        -size.coords.scale(SCALE_TO_PHYSICS) / 2f32,
+                        0.0,
+                    ));
+            } else if assets.bush_objects.contains(&object.id) {
+                let size = assets.object_sizes[&object.id];
+                battle.bushes.push(Bush::new(object, size));
+            }
+        }
+        //add players
+        for index in 0..battle.players.len() {
+            let (x, y, rotation) = battle.spawn_point(index);
+            battle.add_tank(index, &assets.bodies, x, y, rotation);
+        }
+        battle
+    }
+
+    //Send rate is rounded to a whole number of ticks
+    fn set_rates(&mut self, tick_rate: u32, send_rate: u32) {
+        self.tick_time = 1f32 / tick_rate.max(1) as f32;
+        self.send_interval = (tick_rate as f32 / send_rate.max(1) as f32)
+            .round()
+            .clamp(1f32, u16::MAX as f32) as u16;
+    }
+
+    //Players still wait for the battle to start
+    fn set_battle_time(&mut self, time: f32) {
+        self.time += time - self.battle_time;
+        self.battle_time = time;
+    }
+
+    //Seconds until the next tick is due
+    fn next_tick(&self) -> f32 {
+        self.tick_time - self.accumulator - self.step.elapsed().as_secs_f32()
+    }
+
+    //Runs fixed ticks for the wall clock time passed since the last call, returns
+    //true when the battle is over. Time over `MAX_CATCH_UP_TICKS` is dropped, so
+    //an overloaded battle slows down instead of freezing the worker
+    fn advance(&mut self) -> bool {
+        self.accumulator += self.step.elapsed().as_secs_f32();
+        self.step = Instant::now();
+        self.accumulator = self
+            .accumulator
+            .min(self.tick_time * MAX_CATCH_UP_TICKS as f32);
+        while self.accumulator >= self.tick_time {
+            self.accumulator -= self.tick_time;
+            if self.update(self.tick_time) {
+                return true;
+            }
+        }
+        false
+    }
+
+    //Advances the battle by `step` seconds, returns true when it is over
+    fn update(&mut self, step: f32) -> bool {
+        //Bots and absent players act between ticks like clients do, so replays
+        //apply their inputs before the same tick
+        if self.time < self.battle_time {
+            self.control_bots(step);
+            self.update_absence(step);
+        }
+        self.record(ReplayEvent::Tick { dt: step });
+        self.time -= step;
+        if self.time <= 0f32 && self.alive_teams().len() > 1 {
+            self.time_over();
+        }
+        if self.time <= 0f32 || self.alive_teams().len() <= 1 || self.winner.is_some() {
+            return true;
+        }
+        //Clients send inputs while the map is loaded too
+        self.update_monitors(step);
+        if self.time >= self.battle_time {
+            return false;
+        }
+
+        self.world.integration_parameters.dt = step;
+        self.world.integration_parameters.min_ccd_dt = step / 100f32;
+        self.physics_step();
+        self.frame += 1;
+        for player in self.players.iter_mut() {
+            player.stats.cool_down =
+                0f32.max(player.stats.cool_down - step * player.reload_speed());
+            player.revealed = 0f32.max(player.revealed - step);
+        }
+        self.update_abilities(step);
+        self.update_overtime(step);
+        self.update_chat(step);
+
+        self.handle_collisions();
+        self.rewound_hits();
+        self.update_bullets(step);
+        self.update_pickups(step);
+        self.rotate_guns(step);
+        self.record_history();
+        self.update_visibility();
+
+        //notify players
+        if self.frame.is_multiple_of(self.send_interval) {
+            self.send_state();
+        }
+        false
+    }
+
+    fn record(&mut self, event: ReplayEvent) {
+        if let Some(replay) = self.replay.as_mut() {
+            replay.events.push(event);
+        }
+    }
+
     fn physics_step(&mut self) {
         self.world.physics_pipeline.step(
             &self.world.gravity,
@@ -1541,6 +1023,8 @@ impl Battle<'_> {
     }
 
     fn remove_body(&mut self, handle: RigidBodyHandle) {
+        self.lagged_bullets.retain(|f| f.0 != handle);
+        self.bullet_states.remove(&handle);
         self.world.bodies.remove(
             handle,
             &mut self.world.islands,
@@ -1550,6 +1034,678 @@ impl Battle<'_> {
             true,
         );
     }
+
+    fn player_index(&self, id: i64) -> Option<usize> {
+        self.players.iter().position(|f| f.player.id == id)
+    }
+
+    fn alive_teams(&self) -> Vec<u8> {
+        let mut teams: Vec<u8> = self
+            .players
+            .iter()
+            .filter(|f| f.stats.hp > 0)
+            .map(|f| f.team)
+            .collect();
+        teams.sort_unstable();
+        teams.dedup();
+        teams
+    }
+
+    //Spawn points of the map are taken in order, by team in team modes.
+    //Without enough points tanks are placed in two rows facing each other: one
+    //row per team, or odd and even slots in free-for-all. Returns x, y in
+    //pixels and rotation in degrees
+    fn spawn_point(&self, index: usize) -> (f32, f32, f32) {
+        let teams = self.mode.teams_count() == 2;
+        let same_team = |i: usize| !teams || self.players[i].team == self.players[index].team;
+        let points: Vec<&SpawnPoint> = self
+            .map
+            .spawn_points
+            .iter()
+            .filter(|f| !teams || f.team == self.players[index].team)
+            .collect();
+        let needed = (0..self.players.len()).filter(|&f| same_team(f)).count();
+        if points.len() >= needed {
+            let point = points[(0..index).filter(|&f| same_team(f)).count()];
+            return (point.x, point.y, point.rotation);
+        }
+
+        let row = |i: usize| {
+            if teams {
+                self.players[i].team as usize
+            } else {
+                i % 2
+            }
+        };
+        let slot = (0..index).filter(|&f| row(f) == row(index)).count();
+        let count = (0..self.players.len())
+            .filter(|&f| row(f) == row(index))
+            .count();
+        let x = self.map.width as f32 * (slot + 1) as f32 / (count + 1) as f32;
+        if row(index) == 0 {
+            (x, self.map.player1_y as f32, 0f32)
+        } else {
+            (x, self.map.player2_y as f32, 180f32)
+        }
+    }
+
+    fn add_tank(&mut self, index: usize, bodies: &BodyEditorLoader, x: f32, y: f32, rotation: f32) {
+        let player = &mut self.players[index];
+        let mut position = Isometry::new(vector![x, y] * SCALE_TO_PHYSICS, 0.0);
+        position.append_rotation_wrt_center_mut(&UnitComplex::new(rotation.to_radians()));
+        let body = RigidBodyBuilder::dynamic()
+            .position(position)
+            .ccd_enabled(true)
+            .user_data(UserData::new(BodyType::Tank, player.player.id).into())
+            .build();
+
+        let collider = bodies.create_collider(
+            &player.tank.id.to_string(),
+            player.tank_info.graphics_info.tank_width as f32 * SCALE_TO_PHYSICS,
+        );
+        player.handle = self.world.bodies.insert(body);
+        let handle = self.world.colliders.insert_with_parent(
+            collider,
+            player.handle,
+            &mut self.world.bodies,
+        );
+        self.world
+            .colliders
+            .get_mut(handle)
+            .unwrap()
+            .set_position_wrt_parent(Isometry::new(
+                -vector![
+                    player.tank_info.graphics_info.tank_width as f32,
+                    player.tank_info.graphics_info.tank_height as f32
+                ] / 2f32
+                    * SCALE_TO_PHYSICS,
+                0.0,
+            ));
+    }
+
+    fn move_player(&mut self, id: i64, position: PlayerPosition) {
+        self.record(ReplayEvent::Position {
+            id,
+            position: position.clone(),
+        });
+        let tick_time = self.tick_time;
+        let player = match self.player_index(id) {
+            Some(index) => &mut self.players[index],
+            None => return,
+        };
+        if player.frame < position.frame_num {
+            player.frame = position.frame_num;
+            player.ack_frame = position.ack_frame.filter(|&f| f <= self.frame);
+            if self.time <= self.battle_time && player.stats.hp > 0 {
+                //Body rotation
+                let player_body = self.world.bodies.get_mut(player.handle).unwrap();
+                let back_angle = revert_angle_by_y(player_body.rotation().angle());
+
+                let mut diff = 0f32;
+                if position.body_rotation != 0f32 {
+                    diff = position.body_rotation - player_body.rotation().angle();
+                    diff = diff.rem_euclid(360f32.to_radians()) - 180f32.to_radians();
+                }
+                let mut back_diff = 0f32;
+                if position.body_rotation != 0f32 {
+                    back_diff = position.body_rotation - back_angle;
+                    back_diff = back_diff.rem_euclid(360f32.to_radians()) - 180f32.to_radians();
+                }
+                let ang_vel = player
+                    .tank_info
+                    .characteristics
+                    .body_rotate_degrees
+                    .to_radians();
+
+                let speed = player.tank_info.characteristics.velocity * player.speed_multiplier();
+                player_body.set_angvel(0f32, true);
+                if position.body_rotation != 0f32 {
+                    if diff.abs() < back_diff.abs() {
+                        let alpha = player_body.rotation().angle();
+                        let direction = direction_by_2_angles(alpha, position.body_rotation);
+                        if diff.abs() <= ang_vel * tick_time {
+                            player_body.set_angvel(0f32, true);
+                        } else {
+                            player_body.set_angvel(direction * ang_vel, true);
+                        }
+                    } else {
+                        let direction = direction_by_2_angles(back_angle, position.body_rotation);
+                        if back_diff.abs() <= ang_vel * tick_time {
+                            player_body.set_angvel(0f32, true);
+                        } else {
+                            player_body.set_angvel(direction * ang_vel, true);
+                        }
+                    }
+                }
+                if position.moving {
+                    if diff.abs() > back_diff.abs() {
+                        let velocity = vector![
+                            speed
+                                * SCALE_TO_PHYSICS
+                                * (player_body.rotation().angle() - 90f32.to_radians()).cos(),
+                            speed
+                                * SCALE_TO_PHYSICS
+                                * (player_body.rotation().angle() - 90f32.to_radians()).sin()
+                        ];
+                        player_body.set_linvel(velocity, true);
+                    } else {
+                        let velocity = vector![
+                            speed
+                                * 0.5f32
+                                * SCALE_TO_PHYSICS
+                                * (back_angle - 90f32.to_radians()).cos(),
+                            speed
+                                * 0.5f32
+                                * SCALE_TO_PHYSICS
+                                * (back_angle - 90f32.to_radians()).sin()
+                        ];
+                        player_body.set_linvel(velocity, true);
+                    }
+                } else {
+                    player_body.set_linvel(Vector::zeros(), true);
+                }
+
+                //Gun rotation
+                player.stats.gun_rotation = position.gun_rotation;
+            }
+        }
+    }
+
+    fn shoot(&mut self, id: i64) {
+        self.record(ReplayEvent::Shoot { id });
+        let index = match self.player_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        //Gun is rewound to the frame the shooter saw
+        let lag = self.lag(index);
+        let (tank_position, gun_angle) = self.rewound_transform(index, lag);
+        let player = &mut self.players[index];
+        if player.stats.cool_down == 0f32 && player.stats.hp > 0 && self.time <= self.battle_time {
+            let offsets = player.shot_offsets();
+            player.stats.shots += offsets.len() as i32;
+            player.revealed = REVEAL_TIME;
+            player.stats.cool_down = player.tank_info.characteristics.reloading;
+            for offset in offsets {
+                self.spawn_bullet(index, tank_position, gun_angle + offset, lag);
+            }
+        }
+    }
+
+    //Bullet leaves the gun turned by `gun_angle` relative to the tank
+    fn spawn_bullet(
+        &mut self,
+        index: usize,
+        tank_position: Isometry<Real>,
+        gun_angle: f32,
+        lag: u16,
+    ) {
+        let player = &self.players[index];
+        let mut point = tank_position.translation.vector;
+        let gun_angle = gun_angle + tank_position.rotation.angle();
+
+        point -= vector![
+            player.tank_info.graphics_info.tank_width as f32,
+            -(player.tank_info.graphics_info.tank_height as f32)
+        ] / 2f32
+            * SCALE_TO_PHYSICS;
+        let size = self
+            .assets
+            .gun_sizes
+            .get(player.tank_info.graphics_info.tank_gun_name.as_str())
+            .unwrap();
+        let rotation_point = point
+            + vector![
+                (player.tank_info.graphics_info.gun_x + player.tank_info.graphics_info.gun_origin_x)
+                    as f32,
+                -((player.tank_info.graphics_info.gun_y
+                    + player.tank_info.graphics_info.gun_origin_y) as f32)
+            ] * SCALE_TO_PHYSICS;
+        point += vector![
+            player.tank_info.graphics_info.gun_x as f32 + size.x / 2f32,
+            -(player.tank_info.graphics_info.gun_y as f32) - size.y
+        ] * SCALE_TO_PHYSICS;
+        let new_x = (point.x - rotation_point.x) * gun_angle.cos()
+            - (point.y - rotation_point.y) * gun_angle.sin()
+            + rotation_point.x;
+        let new_y = (point.x - rotation_point.x) * gun_angle.sin()
+            + (point.y - rotation_point.y) * gun_angle.cos()
+            + rotation_point.y;
+        point = vector![new_x, new_y];
+        let mut position = Isometry::new(point, 0.0);
+        position.append_rotation_wrt_center_mut(&UnitComplex::new(gun_angle));
+        let velocity = vector![
+            player.tank_info.characteristics.bullet_speed
+                * SCALE_TO_PHYSICS
+                * (position.rotation.angle() - 90f32.to_radians()).cos(),
+            player.tank_info.characteristics.bullet_speed
+                * SCALE_TO_PHYSICS
+                * (position.rotation.angle() - 90f32.to_radians()).sin()
+        ];
+
+        let bullet_body = RigidBodyBuilder::dynamic()
+            .position(position)
+            .linvel(velocity)
+            .ccd_enabled(true)
+            .user_data(UserData::new(BodyType::Bullet, player.player.id).into())
+            .build();
+
+        let bullet_size = self
+            .assets
+            .bullet_sizes
+            .get(player.tank_info.graphics_info.bullet_name.as_str())
+            .unwrap();
+        let bullet_collider = self.assets.bullets.create_collider(
+            &player.tank_info.graphics_info.bullet_name,
+            bullet_size.x * SCALE_TO_PHYSICS,
+        );
+        let bullet_body_handle = self.world.bodies.insert(bullet_body);
+        let handle = self.world.colliders.insert_with_parent(
+            bullet_collider,
+            bullet_body_handle,
+            &mut self.world.bodies,
+        );
+        self.world
+            .colliders
+            .get_mut(handle)
+            .unwrap()
+            .set_position_wrt_parent(Isometry::new(-bullet_size / 2f32 * SCALE_TO_PHYSICS, 0.0));
+        let characteristics = &player.tank_info.characteristics;
+        let time_left = characteristics
+            .bullet_range
+            .map_or(f32::INFINITY, |f| f / characteristics.bullet_speed);
+        self.bullet_states.insert(
+            bullet_body_handle,
+            BulletState {
+                id: self.next_bullet_id,
+                time_left,
+                ricochets: characteristics.ricochets,
+                velocity,
+            },
+        );
+        self.next_bullet_id += 1;
+        self.validate_bullet(index, bullet_body_handle, lag);
+    }
+
+    fn handle_collisions(&mut self) {
+        while let Ok((collision_event, mut point, normal)) = self.collision_recv.try_recv() {
+            // Handle the collision event.
+            if !collision_event.removed() && collision_event.started() {
+                point *= SCALE_TO_PIXELS;
+
+                //One of the bodies could already be removed by previous event
+                let handles = (
+                    self.world
+                        .colliders
+                        .get(collision_event.collider1())
+                        .and_then(|f| f.parent()),
+                    self.world
+                        .colliders
+                        .get(collision_event.collider2())
+                        .and_then(|f| f.parent()),
+                );
+                if let (Some(body1_handle), Some(body2_handle)) = handles {
+                    let data1: UserData = self.world.bodies[body1_handle].user_data.into();
+                    let data2: UserData = self.world.bodies[body2_handle].user_data.into();
+                    if data1.body_type == BodyType::Bullet {
+                        if !self.ricochet(body1_handle, data2, normal) {
+                            self.bullet_collision(body1_handle, data1, body2_handle, data2, point);
+                        }
+                    } else if data2.body_type == BodyType::Bullet
+                        && !self.ricochet(body2_handle, data1, normal)
+                    {
+                        self.bullet_collision(body2_handle, data2, body1_handle, data1, point);
+                    }
+                }
+            }
+        }
+    }
+
+    fn bullet_collision(
+        &mut self,
+        bullet_handle: RigidBodyHandle,
+        bullet: UserData,
+        other_handle: RigidBodyHandle,
+        other: UserData,
+        point: Point<Real>,
+    ) {
+        self.remove_body(bullet_handle);
+        self.explosion(point, other.body_type == BodyType::Tank);
+        let shooter = match self.player_index(bullet.id) {
+            Some(shooter) => shooter,
+            None => return,
+        };
+        let direct = match other.body_type {
+            BodyType::Bullet => {
+                self.remove_body(other_handle);
+                return;
+            }
+            BodyType::Tank => match self.player_index(other.id) {
+                Some(target) => {
+                    let damage = self.players[shooter].stats.damage;
+                    self.apply_hit(shooter, target, damage)
+                }
+                None => false,
+            },
+            BodyType::Other if other.id > 0 => {
+                self.damage_object(bullet.id, other.id as u32 - 1);
+                false
+            }
+            _ => false,
+        };
+        let target = (other.body_type == BodyType::Tank).then_some(other.id);
+        let splashed = self.splash(shooter, point, target);
+        if direct || splashed {
+            self.players[shooter].stats.succeeded_shots += 1;
+        }
+    }
+
+    fn damage_object(&mut self, shooter_id: i64, index: u32) {
+        let damage = match self.player_index(shooter_id) {
+            Some(shooter) => self.players[shooter].stats.damage,
+            None => return,
+        };
+        if let Some((hp, handle)) = self.objects_hp.get_mut(&index) {
+            *hp -= damage;
+            if *hp <= 0 {
+                let handle = *handle;
+                self.objects_hp.remove(&index);
+                self.remove_body(handle);
+                self.destroyed_objects.push(index);
+                self.record(ReplayEvent::ObjectDestroyed { index });
+                self.broadcast(&Packet::ObjectDestroyed { index });
+            }
+        }
+    }
+
+    //Returns true if the target was damaged
+    fn apply_hit(&mut self, shooter: usize, target: usize, damage: i32) -> bool {
+        //No friendly fire and no hits on destroyed tanks
+        if self.players[shooter].team == self.players[target].team
+            || self.players[target].stats.hp == 0
+        {
+            return false;
+        }
+        let damage = if self.players[target].shielded() {
+            0
+        } else {
+            self.players[target].stats.hp.min(damage)
+        };
+        self.players[target].stats.hp -= damage;
+        self.players[target].stats.damage_taken += damage;
+        self.players[shooter].stats.damage_dealt += damage;
+        if damage > 0 {
+            self.overtime_hit(shooter);
+        }
+
+        if self.players[target].stats.hp == 0 {
+            self.destroy_tank(target);
+        }
+        true
+    }
+
+    //Destroyed tank stays on the field as an obstacle
+    fn destroy_tank(&mut self, index: usize) {
+        self.players[index].stats.hp = 0;
+        let body = &mut self.world.bodies[self.players[index].handle];
+        body.set_linvel(Vector::zeros(), true);
+        body.set_angvel(0f32, true);
+        body.set_body_type(RigidBodyType::Fixed);
+    }
+
+    fn rotate_guns(&mut self, step: f32) {
+        for player in self.players.iter_mut().filter(|f| f.stats.hp > 0) {
+            if player.stats.gun_rotation != 0f32 {
+                let gun_angle =
+                    player.stats.gun_angle + self.world.bodies[player.handle].rotation().angle();
+                let mut diff = player.stats.gun_rotation - gun_angle;
+                diff += 180f32.to_radians();
+                diff = diff.rem_euclid(360f32.to_radians()) - 180f32.to_radians();
+                let ang_vel = player
+                    .tank_info
+                    .characteristics
+                    .gun_rotate_degrees
+                    .to_radians();
+
+                if diff.abs() >= ang_vel * step {
+                    player.stats.gun_angle += diff.signum() * ang_vel * step;
+                }
+            }
+        }
+    }
+
+    //Flying bullets grouped by the id of the player who shot them
+    fn bullets(&self) -> HashMap<i64, Vec<BulletData>> {
+        let mut bullets: HashMap<i64, Vec<BulletData>> = HashMap::new();
+        for rigid_body_handle in self.world.islands.active_dynamic_bodies() {
+            let rigid_body = &self.world.bodies[*rigid_body_handle];
+            let data: UserData = rigid_body.user_data.into();
+            if data.body_type == BodyType::Bullet {
+                bullets.entry(data.id).or_default().push(BulletData {
+                    id: self.bullet_states[rigid_body_handle].id,
+                    x: rigid_body.translation().x * SCALE_TO_PIXELS,
+                    y: rigid_body.translation().y * SCALE_TO_PIXELS,
+                    rotation: rigid_body.rotation().angle().to_degrees(),
+                });
+            }
+        }
+        bullets
+    }
+
+    fn player_data(
+        &self,
+        index: usize,
+        own: bool,
+        bullets: &HashMap<i64, Vec<BulletData>>,
+    ) -> GamePlayerData {
+        let player = &self.players[index];
+        let position = self.world.bodies[player.handle].position();
+        GamePlayerData {
+            visible: true,
+            x: position.translation.x * SCALE_TO_PIXELS,
+            y: position.translation.y * SCALE_TO_PIXELS,
+            body_rotation: position.rotation.angle().to_degrees(),
+            gun_rotation: player.stats.gun_angle.to_degrees(),
+            hp: player.stats.hp as u16,
+            cool_down: if own { player.stats.cool_down } else { 0f32 },
+            bullets: bullets.get(&player.player.id).cloned().unwrap_or_default(),
+            effect: player.stats.effect,
+            ability_time: player.stats.ability_time,
+            ability_cool_down: if own {
+                player.stats.ability_cool_down
+            } else {
+                0f32
+            },
+        }
+    }
+
+    //Game state from the point of view of player with given index
+    fn game_packet(&self, index: usize, bullets: &HashMap<i64, Vec<BulletData>>) -> GamePacket {
+        let bullets = self.visible_bullets(index, bullets);
+        GamePacket {
+            time_left: self.time as u16,
+            frame_num: self.frame,
+            my_data: self.player_data(index, true, &bullets),
+            others_data: (0..self.players.len())
+                .filter(|&f| f != index)
+                .map(|f| {
+                    if self.is_visible(index, f) {
+                        self.player_data(f, false, &bullets)
+                    } else {
+                        self.hidden_player_data(f, &bullets)
+                    }
+                })
+                .collect(),
+            phase: self.phase,
+        }
+    }
+
+    fn map_found_packet(&self, index: usize, wait_time: f32) -> Packet {
+        let player = &self.players[index];
+        Packet::MapFoundResponse {
+            wait_time,
+            map: self.map.clone(),
+            mode: self.mode,
+            my_team: player.team,
+            my_tank: player.tank.clone(),
+            participants: self
+                .players
+                .iter()
+                .enumerate()
+                .filter(|f| f.0 != index)
+                .map(|(_, f)| BattleParticipant {
+                    nickname: f.player.nickname.clone().unwrap(),
+                    tank: f.tank.clone(),
+                    team: f.team,
+                })
+                .collect(),
+            initial_packet: self.game_packet(index, &HashMap::new()),
+            destroyed_objects: self.destroyed_objects.clone(),
+        }
+    }
+
+    fn send_to(&self, index: usize, packet: &Packet) {
+        if let Some(conn) = &self.players[index].conn {
+            send_packet(conn, packet);
+        }
+    }
+
+    //Sends to spectators as well
+    fn broadcast(&self, packet: &Packet) {
+        for index in 0..self.players.len() {
+            self.send_to(index, packet);
+        }
+        for spectator in &self.spectators {
+            send_packet(&spectator.conn, packet);
+        }
+    }
+
+    fn send_state(&mut self) {
+        let bullets = self.bullets();
+        self.send_spectators_state(&bullets);
+        for index in 0..self.players.len() {
+            self.send_snapshot(index, &bullets);
+        }
+    }
+
+    fn battle_results<R: Rng>(
+        &self,
+        index: usize,
+        result: BattleResult,
+        gen: &mut R,
+    ) -> BattleResultStruct {
+        let player = &self.players[index];
+        let opponents: Vec<&WorldPlayer> = self
+            .players
+            .iter()
+            .filter(|f| f.team != player.team)
+            .collect();
+        let opponents_efficiency = opponents
+            .iter()
+            .map(|f| f.player.get_efficiency())
+            .sum::<f32>()
+            / opponents.len() as f32;
+        let opponents_rank =
+            opponents.iter().map(|f| f.player.rank_level).sum::<i32>() / opponents.len() as i32;
+
+        let acc = player.stats.succeeded_shots as f32 / player.stats.shots as f32;
+        let ratio = player.stats.damage_dealt as f32 / player.stats.damage_taken as f32;
+        let eff = (acc + 0.5f32) * if ratio.is_normal() { ratio } else { 1f32 };
+
+        let mut results = BattleResultStruct {
+            result,
+            trophies: 0,
+            xp: ((if eff.is_normal() { eff } else { 0f32 }) * 15f32) as i32,
+            coins: 0,
+            damage_dealt: player.stats.damage_dealt,
+            damage_taken: player.stats.damage_taken,
+            accuracy: if acc.is_normal() { acc } else { 0f32 },
+            efficiency: if eff.is_normal() { eff } else { 0f32 },
+        };
+        match results.result {
+            BattleResult::Victory => {
+                results.trophies =
+                    30 + (opponents_efficiency - player.player.get_efficiency()) as i32;
+                results.coins = gen.gen_range(70..=100)
+                    + gen.gen_range(10..=15) * (opponents_rank - player.player.rank_level);
+            }
+            BattleResult::Defeat => {
+                results.trophies =
+                    -30 - (player.player.get_efficiency() - opponents_efficiency) as i32;
+                results.coins = gen.gen_range(15..=20)
+                    + gen.gen_range(10..=15) * (opponents_rank - player.player.rank_level);
+            }
+            BattleResult::Draw => {
+                results.xp = 0;
+            }
+        }
+        if self.private.is_some() {
+            results.trophies = 0;
+            results.xp = 0;
+            results.coins = 0;
+        }
+        results
+    }
+
+    //Winner is decided in overtime or is the only team with tanks left. Without
+    //winner teams that still have tanks get a draw and the rest are defeated
+    fn results<R: Rng>(&self, gen: &mut R) -> Vec<BattleResultStruct> {
+        let alive_teams = self.alive_teams();
+        let winner = self.winner();
+        (0..self.players.len())
+            .map(|index| {
+                let team = self.players[index].team;
+                let result = match winner {
+                    Some(winner) if winner == team => BattleResult::Victory,
+                    None if alive_teams.contains(&team) => BattleResult::Draw,
+                    _ => BattleResult::Defeat,
+                };
+                self.battle_results(index, result, gen)
+            })
+            .collect()
+    }
+
+    fn send_results<R: Rng>(&mut self, gen: &mut R) {
+        let results = self.results(gen);
+        let replay_id = self.replay.as_ref().map(|f| f.id);
+        for (index, results) in results.into_iter().enumerate() {
+            self.record(ReplayEvent::Result {
+                id: self.players[index].player.id,
+                result: results.clone(),
+            });
+            let profile = &mut self.players[index].player;
+            profile.battles_count += 1;
+            if results.result == BattleResult::Victory {
+                profile.victories_count += 1;
+            }
+            profile.trophies = 0.max(profile.trophies + results.trophies);
+            profile.xp += results.xp;
+            profile.coins += results.coins;
+            let xp_bound = (3f32.powf(profile.rank_level as f32 / 10f32)
+                * profile.rank_level as f32
+                * 50f32) as i32;
+            if profile.xp >= xp_bound {
+                profile.xp -= xp_bound;
+                profile.rank_level += 1;
+            }
+            profile.accuracy = (profile.accuracy * (profile.battles_count as f32 - 1f32)
+                + results.accuracy)
+                / profile.battles_count as f32;
+            profile.damage_dealt = (profile.damage_dealt * profile.battles_count
+                + results.damage_dealt)
+                / profile.battles_count;
+            profile.damage_taken = (profile.damage_taken * profile.battles_count
+                + results.damage_taken)
+                / profile.battles_count;
+
+            let data = Packet::BattleResultResponse {
+                profile: (**profile).clone(),
+                result: results,
+                replay_id,
+            };
+            self.send_to(index, &data);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1583,67 +1739,93 @@ struct CircleModel {
 }
 
 impl BodyEditorLoader {
+    fn load(path: &str) -> Result<Self> {
+        let data =
+            std::fs::read_to_string(path).wrap_err_with(|| format!("failed to read {}", path))?;
+        Self::from_json(&data).wrap_err_with(|| format!("failed to parse {}", path))
+    }
+
     // -------------------------------------------------------------------------
     // Json reading process
     // -------------------------------------------------------------------------
     fn read_rigid_body(json_value: &Value) -> Result<RigidBodyModel> {
         let mut rb_model = RigidBodyModel::default();
-        rb_model.name = String::from_str(json_value["name"].as_str().unwrap())?;
-        rb_model.image_path = String::from_str(json_value["imagePath"].as_str().unwrap())?;
+        rb_model.name = json_string(json_value, "name")?;
+        rb_model.image_path = json_string(json_value, "imagePath")?;
 
         let origin = &json_value["origin"];
-        rb_model.origin = point![
-            origin["x"].as_f64().unwrap() as f32,
-            origin["y"].as_f64().unwrap() as f32
-        ];
+        rb_model.origin = point![json_number(origin, "x")?, json_number(origin, "y")?];
 
         // polygons
-        let polygons = &json_value["polygons"];
-        for polygon in polygons.as_array().unwrap() {
-            let mut polygon_model = PolygonModel::default();
-
-            for vertex in polygon.as_array().unwrap() {
-                let vec = point![
-                    vertex["x"].as_f64().unwrap() as f32,
-                    vertex["y"].as_f64().unwrap() as f32
-                ];
-                polygon_model.vertices.push(vec);
-            }
+        for (index, polygon) in json_array(json_value, "polygons")?.iter().enumerate() {
+            let polygon_model =
+                Self::read_polygon(polygon).wrap_err_with(|| format!("polygon {}", index))?;
             rb_model.polygons.push(polygon_model);
         }
 
         // circles
-        let circles = &json_value["circles"];
-        for circle in circles.as_array().unwrap() {
-            let mut circle_model = CircleModel::default();
-
-            circle_model.center = point![
-                circle["cx"].as_f64().unwrap() as f32,
-                circle["cy"].as_f64().unwrap() as f32
-            ];
-            circle_model.radius = circle["cr"].as_f64().unwrap() as f32;
-
+        for (index, circle) in json_array(json_value, "circles")?.iter().enumerate() {
+            let circle_model =
+                Self::read_circle(circle).wrap_err_with(|| format!("circle {}", index))?;
             rb_model.circles.push(circle_model);
         }
         Ok(rb_model)
     }
 
+    fn read_polygon(json_value: &Value) -> Result<PolygonModel> {
+        let mut polygon_model = PolygonModel::default();
+
+        let vertices = match json_value.as_array() {
+            Some(f) => f,
+            None => bail!("not an array"),
+        };
+        for vertex in vertices {
+            let vec = point![json_number(vertex, "x")?, json_number(vertex, "y")?];
+            polygon_model.vertices.push(vec);
+        }
+        //Colliders are built from convex hulls of the polygons
+        if polygon_model.vertices.len() < 3
+            || SharedShape::convex_hull(&polygon_model.vertices).is_none()
+        {
+            bail!("no convex hull");
+        }
+        Ok(polygon_model)
+    }
+
+    fn read_circle(json_value: &Value) -> Result<CircleModel> {
+        let mut circle_model = CircleModel::default();
+
+        circle_model.center = point![
+            json_number(json_value, "cx")?,
+            json_number(json_value, "cy")?
+        ];
+        circle_model.radius = json_number(json_value, "cr")?;
+        Ok(circle_model)
+    }
+
     fn from_json(str: &str) -> Result<Self> {
         let mut model = Model::default();
 
         let map: Value = serde_json::from_str(str)?;
 
-        let body_elem = &map["rigidBodies"];
-
-        for bodies in body_elem.as_array().unwrap() {
-            let body = Self::read_rigid_body(bodies)?;
+        for (index, body) in json_array(&map, "rigidBodies")?.iter().enumerate() {
+            let name = body["name"]
+                .as_str()
+                .map_or(index.to_string(), String::from);
+            let body = Self::read_rigid_body(body).wrap_err_with(|| format!("body {}", name))?;
             model.rigid_bodies.insert(body.name.to_owned(), body);
         }
         Ok(Self { model })
     }
 
+    //Bodies of every tank, bullet and map object are checked by `Assets::problems`
+    //before the server starts, hulls are checked when the file is read
     fn create_collider(&self, name: &str, scale: Real) -> Collider {
-        let rb_model = self.model.rigid_bodies.get(name).unwrap();
+        let rb_model = self
+            .model
+            .rigid_bodies
+            .get(name)
+            .unwrap_or_else(|| panic!("body {} is missing", name));
 
         let mut shapes = Vec::new();
         //polygons
@@ -1657,7 +1839,7 @@ impl BodyEditorLoader {
                     f
                 })
                 .collect();
-            let shape = SharedShape::convex_hull(&vertices).unwrap();
+            let shape = SharedShape::convex_hull(&vertices).expect("scale must be positive");
             shapes.push((Isometry::<Real>::rotation(0f32), shape));
         }
 
@@ -1680,3 +1862,24 @@ impl BodyEditorLoader {
         self.model.rigid_bodies.contains_key(name)
     }
 }
+
+fn json_string(value: &Value, field: &str) -> Result<String> {
+    match value[field].as_str() {
+        Some(f) => Ok(f.to_owned()),
+        None => bail!("{} is not a string", field),
+    }
+}
+
+fn json_number(value: &Value, field: &str) -> Result<f32> {
+    match value[field].as_f64() {
+        Some(f) => Ok(f as f32),
+        None => bail!("{} is not a number", field),
+    }
+}
+
+fn json_array<'a>(value: &'a Value, field: &str) -> Result<&'a Vec<Value>> {
+    match value[field].as_array() {
+        Some(f) => Ok(f),
+        None => bail!("{} is not an array", field),
+    }
+}
@@ -0,0 +1,99 @@
+use super::{Battle, ReplayEvent, WorldPlayer};
+use crate::data::AbilityKind;
+
+const SPEED_BURST: f32 = 1.6f32;
+//Angle between the bullets of a triple shot, in degrees
+const TRIPLE_SHOT_SPREAD: f32 = 10f32;
+
+impl WorldPlayer<'_> {
+    pub(super) fn ability_active(&self, kind: AbilityKind) -> bool {
+        self.stats.ability_time > 0f32
+            && self
+                .tank_info
+                .characteristics
+                .ability
+                .as_ref()
+                .is_some_and(|f| f.kind == kind)
+    }
+
+    pub(super) fn speed_multiplier(&self) -> f32 {
+        if self.ability_active(AbilityKind::SpeedBurst) {
+            SPEED_BURST
+        } else {
+            1f32
+        }
+    }
+
+    //Gun angles of the bullets of one shot relative to the gun, in radians
+    pub(super) fn shot_offsets(&self) -> Vec<f32> {
+        if self.ability_active(AbilityKind::TripleShot) {
+            let spread = TRIPLE_SHOT_SPREAD.to_radians();
+            vec![-spread, 0f32, spread]
+        } else {
+            vec![0f32]
+        }
+    }
+}
+
+impl Battle<'_> {
+    pub(super) fn update_abilities(&mut self, step: f32) {
+        for player in self.players.iter_mut() {
+            player.stats.ability_time = 0f32.max(player.stats.ability_time - step);
+            player.stats.ability_cool_down = 0f32.max(player.stats.ability_cool_down - step);
+        }
+    }
+
+    pub(super) fn use_ability(&mut self, id: i64) {
+        self.record(ReplayEvent::Ability { id });
+        let index = match self.player_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        let player = &mut self.players[index];
+        let ability = match &player.tank_info.characteristics.ability {
+            Some(ability) => ability,
+            None => return,
+        };
+        if player.stats.ability_cool_down == 0f32
+            && player.stats.hp > 0
+            && self.time <= self.battle_time
+        {
+            player.stats.ability_time = ability.duration;
+            player.stats.ability_cool_down = ability.cool_down;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        data::{AbilityKind, TankAbility},
+        physics::simulation::{Fixture, SimulationInput},
+    };
+
+    #[test]
+    fn test_triple_shot_ability() {
+        let mut fixture = Fixture::load();
+        fixture.tanks[0].characteristics.ability = Some(TankAbility {
+            kind: AbilityKind::TripleShot,
+            cool_down: 10f32,
+            duration: 2f32,
+        });
+        let mut sim = fixture.duel();
+        //Ability is loaded like the gun
+        sim.input(0, SimulationInput::Ability);
+        assert_eq!(sim.battle.players[0].stats.ability_time, 0f32);
+
+        sim.battle.players[0].stats.ability_cool_down = 0f32;
+        sim.battle.players[0].stats.cool_down = 0f32;
+        sim.input(0, SimulationInput::Ability);
+        sim.input(0, SimulationInput::Shoot);
+        assert_eq!(sim.battle.bullet_states.len(), 3);
+        assert_eq!(sim.battle.players[0].stats.shots, 3);
+        //Used ability can't be used again until its cool down is over
+        sim.step();
+        sim.input(0, SimulationInput::Ability);
+        assert!(sim.battle.players[0].stats.ability_time < 2f32);
+        assert!(sim.state(0).ability_cool_down > 0f32);
+    }
+}
@@ -0,0 +1,204 @@
+use quinn::VarInt;
+use tracing::warn;
+
+use super::{Battle, ReplayEvent};
+use crate::data::PlayerPosition;
+
+//Clients send a position every frame and shoot or use abilities a few times
+//a second at most, inputs over the limits are dropped
+const MAX_POSITIONS_PER_SECOND: u32 = 90;
+const MAX_ACTIONS_PER_SECOND: u32 = 10;
+//Frames a client can skip between two positions, more is scored but applied,
+//so a client that froze for a while is not locked out
+const MAX_FRAME_JUMP: u16 = 600;
+//In radians, real clients send angles within a turn or two
+const MAX_ROTATION: f32 = 4f32 * std::f32::consts::PI;
+const INVALID_SCORE: f32 = 10f32;
+const RATE_SCORE: f32 = 1f32;
+const JUMP_SCORE: f32 = 5f32;
+//Score points forgiven per second
+const SCORE_DECAY: f32 = 2f32;
+//Player is flagged in the database at this score and kicked at the next one
+const FLAG_SCORE: f32 = 40f32;
+const KICK_SCORE: f32 = 100f32;
+const KICK_CODE: u32 = 1;
+
+//Anomalies in the inputs of a connected player during the battle
+#[derive(Default)]
+pub(super) struct InputMonitor {
+    window: f32,
+    positions: u32,
+    actions: u32,
+    score: f32,
+    //Highest score, stored with the player after the battle
+    peak: f32,
+}
+
+impl Battle<'_> {
+    pub(super) fn update_monitors(&mut self, step: f32) {
+        for player in self.players.iter_mut() {
+            let monitor = &mut player.monitor;
+            monitor.score = 0f32.max(monitor.score - SCORE_DECAY * step);
+            monitor.window += step;
+            if monitor.window >= 1f32 {
+                monitor.window = 0f32;
+                monitor.positions = 0;
+                monitor.actions = 0;
+            }
+        }
+    }
+
+    //Returns false if the position must not be applied
+    pub(super) fn check_position(&mut self, id: i64, position: &PlayerPosition) -> bool {
+        let index = match self.player_index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let player = &mut self.players[index];
+        player.monitor.positions += 1;
+        let rotations = [position.body_rotation, position.gun_rotation];
+        let (score, accepted) = if rotations
+            .iter()
+            .any(|f| !f.is_finite() || f.abs() > MAX_ROTATION)
+        {
+            (INVALID_SCORE, false)
+        } else if player.monitor.positions > MAX_POSITIONS_PER_SECOND {
+            (RATE_SCORE, false)
+        } else if player.frame > 0
+            && position.frame_num > player.frame.saturating_add(MAX_FRAME_JUMP)
+        {
+            (JUMP_SCORE, true)
+        } else {
+            (0f32, true)
+        };
+        self.add_score(index, score) && accepted
+    }
+
+    //Shots and abilities, returns false if the action must be dropped
+    pub(super) fn check_action(&mut self, id: i64) -> bool {
+        let index = match self.player_index(id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let monitor = &mut self.players[index].monitor;
+        monitor.actions += 1;
+        if monitor.actions > MAX_ACTIONS_PER_SECOND {
+            self.add_score(index, RATE_SCORE);
+            return false;
+        }
+        true
+    }
+
+    //Returns false if the player was kicked
+    fn add_score(&mut self, index: usize, score: f32) -> bool {
+        if score == 0f32 {
+            return true;
+        }
+        let player = &mut self.players[index];
+        let monitor = &mut player.monitor;
+        monitor.score += score;
+        if monitor.peak < FLAG_SCORE && monitor.score >= FLAG_SCORE {
+            warn!(
+                "player {} is flagged for suspicious inputs",
+                player.player.id
+            );
+        }
+        monitor.peak = monitor.peak.max(monitor.score);
+        if monitor.score < KICK_SCORE || player.stats.hp == 0 {
+            return true;
+        }
+
+        warn!(
+            "player {} is kicked for suspicious inputs",
+            player.player.id
+        );
+        if let Some(conn) = &player.conn {
+            conn.close(VarInt::from_u32(KICK_CODE), b"suspicious inputs");
+        }
+        player.connected = false;
+        let id = player.player.id;
+        self.kick(id);
+        false
+    }
+
+    //Kicked tank is destroyed, so the player loses the battle
+    pub(super) fn kick(&mut self, id: i64) {
+        self.record(ReplayEvent::Kick { id });
+        if let Some(index) = self.player_index(id) {
+            self.destroy_tank(index);
+        }
+    }
+
+    //Peak score of the battle is added to the score of the player. Private
+    //matches count too, cheating is not less of a cheat among friends
+    pub(super) fn store_anomalies(&mut self) {
+        for player in self.players.iter_mut().filter(|f| f.bot.is_none()) {
+            player.player.anomaly_score += player.monitor.peak;
+            if player.monitor.peak >= FLAG_SCORE {
+                player.player.flagged = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{
+        simulation::{Fixture, TICK_TIME},
+        WAIT_TIME,
+    };
+
+    #[test]
+    fn test_invalid_inputs_get_player_kicked() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        let id = sim.battle.players[0].player.id;
+        let valid = PlayerPosition {
+            frame_num: 1,
+            body_rotation: 1f32,
+            gun_rotation: 0f32,
+            moving: true,
+            ack_frame: None,
+        };
+        assert!(sim.battle.check_position(id, &valid));
+        let invalid = PlayerPosition {
+            body_rotation: f32::NAN,
+            ..valid
+        };
+        while sim.battle.players[0].stats.hp > 0 {
+            assert!(!sim.battle.check_position(id, &invalid));
+        }
+        sim.battle.store_anomalies();
+        assert!(sim.battle.players[0].player.flagged);
+        assert!(!sim.battle.players[1].player.flagged);
+    }
+
+    #[test]
+    fn test_inputs_while_map_loads_are_not_scored() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.time += WAIT_TIME;
+        //Client running at twice the tick rate
+        let mut frame = 0;
+        for _ in 0..=(WAIT_TIME / TICK_TIME).ceil() as u32 {
+            for _ in 0..2 {
+                frame += 1;
+                let position = PlayerPosition {
+                    frame_num: frame,
+                    body_rotation: 0f32,
+                    gun_rotation: 0f32,
+                    moving: false,
+                    ack_frame: None,
+                };
+                assert!(sim.battle.check_position(1, &position));
+            }
+            sim.step();
+        }
+        assert!(sim.battle.time < sim.battle.battle_time);
+        sim.battle.store_anomalies();
+        assert_eq!(sim.battle.players[0].monitor.peak, 0f32);
+        assert!(!sim.battle.players[0].player.flagged);
+        assert!(sim.battle.players[0].stats.hp > 0);
+    }
+}
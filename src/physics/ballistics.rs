@@ -0,0 +1,219 @@
+use rapier2d::{na::UnitComplex, prelude::*};
+
+use super::{Battle, BodyType, ReplayEvent, UserData, SCALE_TO_PHYSICS, SCALE_TO_PIXELS};
+use crate::data::Packet;
+
+//Velocity is kept because the physics step stops a bullet at the body it hit
+//before the collision is handled
+pub(super) struct BulletState {
+    pub id: u32,
+    pub time_left: f32,
+    pub ricochets: u32,
+    pub velocity: Vector<Real>,
+}
+
+impl Battle<'_> {
+    //Bullets that flew their range explode where they are
+    pub(super) fn update_bullets(&mut self, step: f32) {
+        let mut expired: Vec<(u32, RigidBodyHandle)> = Vec::new();
+        for (handle, bullet) in self.bullet_states.iter_mut() {
+            bullet.time_left -= step;
+            if bullet.time_left <= 0f32 {
+                expired.push((bullet.id, *handle));
+            }
+        }
+        //Same order in replays and simulations
+        expired.sort_unstable_by_key(|f| f.0);
+        for (_, handle) in expired {
+            let point = self.world.bodies[handle].translation() * SCALE_TO_PIXELS;
+            self.remove_body(handle);
+            self.explosion(point.into(), false);
+        }
+    }
+
+    //Point is in pixels
+    pub(super) fn explosion(&mut self, point: Point<Real>, hit: bool) {
+        self.record(ReplayEvent::Explosion {
+            x: point.x,
+            y: point.y,
+            hit,
+        });
+        self.broadcast(&Packet::Explosion {
+            x: point.x,
+            y: point.y,
+            hit,
+        });
+    }
+
+    //Returns true if the bullet bounced off the body instead of exploding
+    pub(super) fn ricochet(
+        &mut self,
+        handle: RigidBodyHandle,
+        other: UserData,
+        normal: Vector<Real>,
+    ) -> bool {
+        if other.body_type != BodyType::Other || normal.norm() == 0f32 {
+            return false;
+        }
+        let shooter: UserData = self.world.bodies[handle].user_data.into();
+        let max_angle = match self.player_index(shooter.id) {
+            Some(index) => self.players[index].tank_info.characteristics.ricochet_angle,
+            None => return false,
+        };
+        let bullet = match self.bullet_states.get_mut(&handle) {
+            Some(bullet) => bullet,
+            None => return false,
+        };
+        let speed = bullet.velocity.norm();
+        if bullet.ricochets == 0 || speed == 0f32 {
+            return false;
+        }
+        let normal = normal.normalize();
+        //Angle between the path of the bullet and the surface
+        let angle = (bullet.velocity.dot(&normal).abs() / speed).asin();
+        if angle > max_angle.to_radians() {
+            return false;
+        }
+        bullet.ricochets -= 1;
+        bullet.velocity -= 2f32 * bullet.velocity.dot(&normal) * normal;
+
+        //Bullet points where it flies, like at the shot
+        let velocity = bullet.velocity;
+        let body = &mut self.world.bodies[handle];
+        let mut position = *body.position();
+        position.rotation = UnitComplex::new(velocity.y.atan2(velocity.x) + 90f32.to_radians());
+        body.set_position(position, true);
+        body.set_linvel(velocity, true);
+        body.set_angvel(0f32, true);
+        true
+    }
+
+    //Damages enemies around the explosion except the one hit directly,
+    //returns true if anyone was damaged. Point is in pixels
+    pub(super) fn splash(
+        &mut self,
+        shooter: usize,
+        point: Point<Real>,
+        direct: Option<i64>,
+    ) -> bool {
+        let characteristics = &self.players[shooter].tank_info.characteristics;
+        let (radius, falloff) = (
+            characteristics.splash_radius * SCALE_TO_PHYSICS,
+            characteristics.splash_falloff,
+        );
+        if radius <= 0f32 {
+            return false;
+        }
+        let center = point.coords * SCALE_TO_PHYSICS;
+        self.world.query_pipeline.update(
+            &self.world.islands,
+            &self.world.bodies,
+            &self.world.colliders,
+        );
+        let mut targets: Vec<(i64, f32)> = Vec::new();
+        self.world.query_pipeline.intersections_with_shape(
+            &self.world.bodies,
+            &self.world.colliders,
+            &Isometry::translation(center.x, center.y),
+            &Ball::new(radius),
+            QueryFilter::default().exclude_sensors(),
+            |collider| {
+                if let Some(parent) = self.world.colliders[collider].parent() {
+                    let body = &self.world.bodies[parent];
+                    let data: UserData = body.user_data.into();
+                    if data.body_type == BodyType::Tank
+                        && Some(data.id) != direct
+                        && targets.iter().all(|f| f.0 != data.id)
+                    {
+                        let distance = (body.translation() - center).norm() / radius;
+                        targets.push((data.id, distance.min(1f32)));
+                    }
+                }
+                true
+            },
+        );
+
+        let damage = self.players[shooter].stats.damage as f32;
+        let mut damaged = false;
+        for (id, distance) in targets {
+            if let Some(target) = self.player_index(id) {
+                let damage = (damage * (1f32 - falloff * distance)).round() as i32;
+                damaged |= self.apply_hit(shooter, target, damage);
+            }
+        }
+        damaged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::simulation::{Fixture, SimulationInput, TICK_TIME};
+
+    #[test]
+    fn test_bullets_explode_after_their_range() {
+        let mut fixture = Fixture::load();
+        fixture.tanks[0].characteristics.bullet_range = Some(60f32);
+        let flight = 60f32 / fixture.tanks[0].characteristics.bullet_speed;
+        let mut sim = fixture.duel();
+        sim.battle.players[0].stats.cool_down = 0f32;
+        sim.input(0, SimulationInput::Shoot);
+        assert_eq!(sim.battle.bullet_states.len(), 1);
+        for _ in 0..(flight / TICK_TIME).ceil() as u32 + 1 {
+            sim.step();
+        }
+        assert!(sim.battle.bullet_states.is_empty());
+    }
+
+    #[test]
+    fn test_bullets_ricochet_at_shallow_angles() {
+        let mut fixture = Fixture::load();
+        fixture.tanks[0].characteristics.ricochets = 1;
+        fixture.tanks[0].characteristics.ricochet_angle = 30f32;
+        let mut sim = fixture.duel();
+        sim.battle.players[0].stats.cool_down = 0f32;
+        sim.input(0, SimulationInput::Shoot);
+        let (&handle, bullet) = sim.battle.bullet_states.iter().next().unwrap();
+        let velocity = bullet.velocity;
+        let wall = UserData::new(BodyType::Other, 0);
+        //Head-on hit, then a surface 20 degrees to the path of the bullet
+        let normal = Rotation::new(70f32.to_radians()) * velocity;
+        assert!(!sim.battle.ricochet(handle, wall, velocity));
+        assert!(sim.battle.ricochet(handle, wall, normal));
+        let reflected = sim.battle.bullet_states[&handle].velocity;
+        assert!((reflected.norm() - velocity.norm()).abs() < 1e-4);
+        assert!(reflected.dot(&normal) * velocity.dot(&normal) < 0f32);
+        assert_eq!(sim.battle.world.bodies[handle].linvel(), &reflected);
+        assert!(!sim.battle.ricochet(handle, wall, normal));
+    }
+
+    #[test]
+    fn test_splash_damage_falls_off() {
+        let mut fixture = Fixture::load();
+        fixture.tanks[0].characteristics.splash_radius = 100f32;
+        fixture.tanks[0].characteristics.splash_falloff = 0.5f32;
+        let mut sim = fixture.duel();
+        //Colliders are placed by the physics step
+        sim.step();
+        let target = sim.battle.world.bodies[sim.battle.players[1].handle].translation();
+        let center = (target * SCALE_TO_PIXELS).into();
+        let (damage, hp) = (
+            sim.battle.players[0].stats.damage,
+            sim.battle.players[1].stats.hp,
+        );
+        assert!(sim.battle.splash(0, center, None));
+        assert_eq!(sim.battle.players[1].stats.hp, hp - damage);
+        assert!(sim
+            .battle
+            .splash(0, center + Vector::new(50f32, 0f32), None));
+        let half = (damage as f32 * 0.75f32).round() as i32;
+        assert_eq!(sim.battle.players[1].stats.hp, hp - damage - half);
+        assert!(!sim
+            .battle
+            .splash(0, center + Vector::new(500f32, 0f32), None));
+        //Directly hit tank is not damaged again
+        assert!(!sim
+            .battle
+            .splash(0, center, Some(sim.battle.players[1].player.id)));
+    }
+}
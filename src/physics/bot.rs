@@ -0,0 +1,227 @@
+use rand::{seq::SliceRandom, Rng};
+use rapier2d::prelude::*;
+
+use super::{BalancedPlayer, Battle, SCALE_TO_PHYSICS};
+use crate::data::{Player, PlayerPosition, Tank, TANKS};
+
+//Bots reach full difficulty at this amount of trophies
+const MAX_DIFFICULTY_TROPHIES: f32 = 2000f32;
+const MIN_DIFFICULTY: f32 = 0.1f32;
+//Seconds between decisions of the easiest and the hardest bots
+const SLOWEST_REACTION: f32 = 0.8f32;
+const FASTEST_REACTION: f32 = 0.15f32;
+const MAX_AIM_ERROR: f32 = 12f32;
+const AIM_TOLERANCE: f32 = 3f32;
+//In pixels
+const PREFERRED_DISTANCE: f32 = 450f32;
+const LOOK_AHEAD: f32 = 120f32;
+const NICKNAMES: &[&str] = &["Ironside", "Rookie", "Gunner", "Steel", "Tracker", "Warden"];
+
+impl BalancedPlayer {
+    //Random tank with the same level as the opponent's one. Bot keeps
+    //opponent's trophies, they define its difficulty
+    pub fn bot(trophies: i32, level: i32) -> Self {
+        let mut gen = rand::thread_rng();
+        let tank_info = TANKS.get().choose(&mut gen).unwrap();
+        let id = crate::db::ID_GEN.get().lock().real_time_generate();
+        let nickname = format!(
+            "{}{}",
+            NICKNAMES.choose(&mut gen).unwrap(),
+            gen.gen_range(10..100)
+        );
+        let mut player = Player::unregistered(id, nickname);
+        player.trophies = trophies;
+        player.tanks.push(Tank {
+            id: tank_info.id as i32,
+            level,
+            count: 0,
+        });
+        Self(Box::new(player), tank_info.id as i32, None)
+    }
+}
+
+pub(super) struct Bot {
+    difficulty: f32,
+    think_time: f32,
+    aim_error: f32,
+    heading: f32,
+    moving: bool,
+}
+
+impl Bot {
+    pub(super) fn new(trophies: i32) -> Self {
+        Self {
+            difficulty: (trophies as f32 / MAX_DIFFICULTY_TROPHIES).clamp(MIN_DIFFICULTY, 1f32),
+            think_time: 0f32,
+            aim_error: 0f32,
+            heading: 0f32,
+            moving: false,
+        }
+    }
+
+    //Bot sends the same inputs as a client would, so it is also recorded in replays
+    fn control(&mut self, battle: &mut Battle, index: usize, step: f32) {
+        if battle.players[index].stats.hp <= 0 {
+            return;
+        }
+        self.think_time -= step;
+        let think = self.think_time <= 0f32;
+        //Drawn before the battle is borrowed for the decision
+        let roll = if think {
+            battle.bot_gen.gen_range(-1f32..=1f32)
+        } else {
+            0f32
+        };
+        let me = &battle.players[index];
+        let body = &battle.world.bodies[me.handle];
+        let position = *body.translation();
+        //Hidden enemies are searched for, but not shot at
+        let target = battle
+            .players
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.team != me.team && f.stats.hp > 0)
+            .map(|(i, f)| (&battle.world.bodies[f.handle], battle.is_visible(index, i)))
+            .min_by(|a, b| {
+                let (a, b) = (a.0.translation() - position, b.0.translation() - position);
+                a.norm().total_cmp(&b.norm())
+            });
+        let (target, seen) = match target {
+            Some(target) => target,
+            None => return,
+        };
+
+        //Lead the target, flight time is estimated again for the predicted point.
+        //Easier bots lead less
+        let bullet_speed = me.tank_info.characteristics.bullet_speed * SCALE_TO_PHYSICS;
+        let mut aim = *target.translation();
+        for _ in 0..3 {
+            let time = (aim - position).norm() / bullet_speed;
+            aim = target.translation() + target.linvel() * time * self.difficulty;
+        }
+        let to_aim = aim - position;
+        let distance = to_aim.norm();
+        let in_range = me
+            .tank_info
+            .characteristics
+            .bullet_range
+            .is_none_or(|f| distance <= f * SCALE_TO_PHYSICS);
+        let visible =
+            seen && distance > 0f32 && battle.obstacle(position, to_aim, distance).is_none();
+
+        if think {
+            self.think_time =
+                SLOWEST_REACTION - (SLOWEST_REACTION - FASTEST_REACTION) * self.difficulty;
+            self.aim_error = roll * MAX_AIM_ERROR.to_radians() * (1f32 - self.difficulty);
+            self.moving = !visible || !in_range || distance > PREFERRED_DISTANCE * SCALE_TO_PHYSICS;
+
+            //Drive to the target, turning away from the closest obstacles
+            let look_ahead = LOOK_AHEAD * SCALE_TO_PHYSICS;
+            let wanted = angle_of(target.translation() - position);
+            self.heading = [
+                0f32, 30f32, -30f32, 60f32, -60f32, 90f32, -90f32, 135f32, -135f32,
+            ]
+            .into_iter()
+            .map(|f| wanted + f.to_radians())
+            .find(|&f| {
+                let direction = vector![f.sin(), -f.cos()];
+                battle.obstacle(position, direction, look_ahead).is_none()
+            })
+            .unwrap_or(wanted + 180f32.to_radians());
+        }
+
+        let gun_rotation = angle_of(to_aim) + self.aim_error;
+        let gun = me.stats.gun_angle + body.rotation().angle();
+        let aimed = (gun_rotation - gun + 180f32.to_radians()).rem_euclid(360f32.to_radians())
+            - 180f32.to_radians();
+        let ready = me.stats.cool_down == 0f32;
+        //Abilities are used as soon as the bot sees its target
+        let ability =
+            me.tank_info.characteristics.ability.is_some() && me.stats.ability_cool_down == 0f32;
+        let id = me.player.id;
+        let position = PlayerPosition {
+            frame_num: me.frame.wrapping_add(1),
+            body_rotation: if self.moving { self.heading } else { 0f32 },
+            gun_rotation,
+            moving: self.moving,
+            ack_frame: None,
+        };
+        battle.move_player(id, position);
+        if visible && ability {
+            battle.use_ability(id);
+        }
+        if visible && in_range && ready && aimed.abs() <= AIM_TOLERANCE.to_radians() {
+            battle.shoot(id);
+        }
+    }
+}
+
+//Angle of the direction in the same form as tank rotations
+fn angle_of(direction: Vector<Real>) -> f32 {
+    direction.x.atan2(-direction.y)
+}
+
+impl Battle<'_> {
+    pub(super) fn control_bots(&mut self, step: f32) {
+        if self.players.iter().all(|f| f.bot.is_none()) {
+            return;
+        }
+        self.world.query_pipeline.update(
+            &self.world.islands,
+            &self.world.bodies,
+            &self.world.colliders,
+        );
+        for index in 0..self.players.len() {
+            if let Some(mut bot) = self.players[index].bot.take() {
+                bot.control(self, index, step);
+                self.players[index].bot = Some(bot);
+            }
+        }
+    }
+
+    //Distance to the closest wall or map object in the direction
+    fn obstacle(&self, from: Vector<Real>, direction: Vector<Real>, max: f32) -> Option<f32> {
+        let ray = Ray::new(from.into(), direction.normalize());
+        self.world
+            .query_pipeline
+            .cast_ray(
+                &self.world.bodies,
+                &self.world.colliders,
+                &ray,
+                max,
+                true,
+                QueryFilter::only_fixed().exclude_sensors(),
+            )
+            .map(|f| f.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::simulation::Fixture;
+
+    #[test]
+    fn test_bot_defeats_idle_tank() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.players[1].bot = Some(Bot::new(2000));
+        let outcome = sim.run_script(&[]);
+        assert_eq!(outcome.winner, Some(1));
+        assert!(outcome.players[1].succeeded_shots > 0);
+    }
+
+    #[test]
+    fn test_bot_battles_are_deterministic() {
+        let fixture = Fixture::load();
+        let run = || {
+            let mut sim = fixture.duel();
+            sim.battle.players[0].bot = Some(Bot::new(500));
+            sim.battle.players[1].bot = Some(Bot::new(500));
+            sim.run_script(&[])
+        };
+        let outcome = run();
+        assert!(outcome.players.iter().all(|f| f.shots > 0));
+        assert_eq!(outcome, run());
+    }
+}
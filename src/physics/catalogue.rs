@@ -0,0 +1,381 @@
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::eyre::{bail, WrapErr};
+use rapier2d::{parry::query, prelude::*};
+use serde::Deserialize;
+
+use super::{load_tanks, Assets, Battle, Result, WorldPlayer};
+use crate::data::{BattleMode, Map, MapObject, Player, Tank, TankInfo};
+
+const MODES: [BattleMode; 4] = [
+    BattleMode::Duel,
+    BattleMode::TwoVsTwo,
+    BattleMode::ThreeVsThree,
+    BattleMode::FreeForAll,
+];
+
+//Sizes are in pixels of the sprites the bodies in `*.polygons` were made for
+#[derive(Deserialize)]
+struct ObjectCatalogue {
+    objects: Vec<ObjectEntry>,
+}
+
+#[derive(Deserialize)]
+struct ObjectEntry {
+    id: i32,
+    name: String,
+    width: f32,
+    height: f32,
+    //Objects without hp can't be destroyed
+    #[serde(default)]
+    hp: Option<i32>,
+    //Tanks in bushes are hidden from enemies
+    #[serde(default)]
+    bush: bool,
+}
+
+#[derive(Deserialize)]
+struct SizeCatalogue {
+    sizes: Vec<SizeEntry>,
+}
+
+#[derive(Deserialize)]
+struct SizeEntry {
+    name: String,
+    width: f32,
+    height: f32,
+}
+
+#[derive(Deserialize)]
+struct EmoteCatalogue {
+    emotes: Vec<EmoteEntry>,
+}
+
+//Clients show emotes by id, name is for people editing the catalogue
+#[derive(Deserialize)]
+struct EmoteEntry {
+    id: u16,
+    name: String,
+}
+
+pub(super) struct MapObjectCatalogue {
+    pub sizes: HashMap<i32, Point<Real>>,
+    pub hp: HashMap<i32, i32>,
+    pub bushes: HashSet<i32>,
+}
+
+fn read<T: for<'a> Deserialize<'a>>(path: &str) -> Result<T> {
+    let content = std::fs::read(path).wrap_err_with(|| format!("failed to read {}", path))?;
+    serde_json::from_slice(&content).wrap_err_with(|| format!("failed to parse {}", path))
+}
+
+pub(super) fn load_objects(path: &str) -> Result<MapObjectCatalogue> {
+    let catalogue: ObjectCatalogue = read(path)?;
+    let mut res = MapObjectCatalogue {
+        sizes: HashMap::new(),
+        hp: HashMap::new(),
+        bushes: HashSet::new(),
+    };
+    for object in catalogue.objects {
+        if object.width <= 0f32 || object.height <= 0f32 {
+            bail!("{}: object {} has non-positive size", path, object.name);
+        }
+        if res
+            .sizes
+            .insert(object.id, point![object.width, object.height])
+            .is_some()
+        {
+            bail!("{}: object id {} is used twice", path, object.id);
+        }
+        match object.hp {
+            Some(hp) if hp <= 0 => bail!("{}: object {} has non-positive hp", path, object.name),
+            Some(hp) => {
+                res.hp.insert(object.id, hp);
+            }
+            None => {}
+        }
+        if object.bush {
+            res.bushes.insert(object.id);
+        }
+    }
+    Ok(res)
+}
+
+//Sizes of bullets or guns by the name of their sprite
+pub(super) fn load_sizes(path: &str) -> Result<HashMap<String, Vector<Real>>> {
+    let catalogue: SizeCatalogue = read(path)?;
+    let mut res = HashMap::new();
+    for entry in catalogue.sizes {
+        if entry.width <= 0f32 || entry.height <= 0f32 {
+            bail!("{}: {} has non-positive size", path, entry.name);
+        }
+        if res
+            .insert(entry.name.clone(), vector![entry.width, entry.height])
+            .is_some()
+        {
+            bail!("{}: {} is listed twice", path, entry.name);
+        }
+    }
+    Ok(res)
+}
+
+pub(super) fn load_emotes(path: &str) -> Result<HashSet<u16>> {
+    let catalogue: EmoteCatalogue = read(path)?;
+    let mut res = HashSet::new();
+    for emote in catalogue.emotes {
+        if emote.name.is_empty() {
+            bail!("{}: emote {} has no name", path, emote.id);
+        }
+        if !res.insert(emote.id) {
+            bail!("{}: emote id {} is used twice", path, emote.id);
+        }
+    }
+    Ok(res)
+}
+
+//Prints every problem of the assets in the working directory, fails if there are any
+pub fn validate_assets() -> Result<()> {
+    let tanks = load_tanks("Tanks")?;
+    let assets = Assets::load()?;
+    let problems = assets.problems(&tanks);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problems found", problems.len());
+    }
+    println!(
+        "{} maps and {} tanks are valid",
+        assets.maps.len(),
+        tanks.len()
+    );
+    Ok(())
+}
+
+impl Assets {
+    //Every problem that would make a battle panic or start broken, empty if
+    //assets are consistent
+    pub(super) fn problems(&self, tanks: &[TankInfo]) -> Vec<String> {
+        let mut problems = Vec::new();
+        for name in self.map_objects.model.rigid_bodies.keys() {
+            if !name
+                .parse()
+                .is_ok_and(|id: i32| self.object_sizes.contains_key(&id))
+            {
+                problems.push(format!(
+                    "map object body {} has no entry in the map object catalogue",
+                    name
+                ));
+            }
+        }
+        //Spawn points are checked with the largest tank
+        let largest = tanks
+            .iter()
+            .filter(|f| self.bodies.body_exists(&f.id.to_string()))
+            .max_by_key(|f| f.graphics_info.tank_width.max(f.graphics_info.tank_height));
+        for map in &self.maps {
+            let count = problems.len();
+            for (index, object) in map.objects.iter().enumerate() {
+                //Colliders are scaled convex hulls, they can't be flattened
+                if object.scale <= 0f32 {
+                    problems.push(format!(
+                        "map {}: object {} has non-positive scale",
+                        map.name, index
+                    ));
+                }
+                match self.object_sizes.get(&object.id) {
+                    Some(size) if !inside(map, object, *size) => problems.push(format!(
+                        "map {}: object {} is outside of the map",
+                        map.name, index
+                    )),
+                    Some(_) => {}
+                    None => problems.push(format!(
+                        "map {}: object {} has unknown id {}",
+                        map.name, index, object.id
+                    )),
+                }
+            }
+            let (width, height) = (map.width as f32, map.height as f32);
+            for (index, point) in map.spawn_points.iter().enumerate() {
+                if !(0f32..=width).contains(&point.x) || !(0f32..=height).contains(&point.y) {
+                    problems.push(format!(
+                        "map {}: spawn point {} is outside of the map",
+                        map.name, index
+                    ));
+                }
+                if point.team > 1 {
+                    problems.push(format!(
+                        "map {}: spawn point {} has team {}, only 0 and 1 are used",
+                        map.name, index, point.team
+                    ));
+                }
+            }
+            for zone in &map.zones {
+                if zone.width <= 0f32
+                    || zone.height <= 0f32
+                    || zone.x < 0f32
+                    || zone.y < 0f32
+                    || zone.x + zone.width > width
+                    || zone.y + zone.height > height
+                {
+                    problems.push(format!(
+                        "map {}: zone {} is empty or outside of the map",
+                        map.name, zone.name
+                    ));
+                }
+            }
+            let selection = &map.selection;
+            if selection
+                .max_trophies
+                .is_some_and(|f| f < selection.min_trophies)
+            {
+                problems.push(format!(
+                    "map {}: max trophies are below min trophies",
+                    map.name
+                ));
+            }
+            if let (Some(tank), true) = (largest, problems.len() == count) {
+                self.spawn_problems(map, tank, &mut problems);
+            }
+        }
+        for mode in MODES {
+            if !self.maps.iter().any(|f| {
+                let selection = &f.selection;
+                selection.enabled
+                    && selection.weight > 0f32
+                    && (selection.modes.is_empty() || selection.modes.contains(&mode))
+            }) {
+                problems.push(format!("no enabled map for {}", mode));
+            }
+        }
+        for name in self.bullet_sizes.keys() {
+            if !self.bullets.body_exists(name) {
+                problems.push(format!("bullet {} has no body in Bullets.polygons", name));
+            }
+        }
+        for (index, tank) in tanks.iter().enumerate() {
+            if tanks[..index].iter().any(|f| f.id == tank.id) {
+                problems.push(format!("tank id {} is used twice", tank.id));
+            }
+            let info = &tank.graphics_info;
+            let name = &tank.characteristics.name;
+            if !self.bodies.body_exists(&tank.id.to_string()) {
+                problems.push(format!("tank {} has no body in TanksBodies.polygons", name));
+            }
+            if info.tank_width == 0 || info.tank_height == 0 {
+                problems.push(format!("tank {} has zero size", name));
+            }
+            if !self.bullet_sizes.contains_key(&info.bullet_name) {
+                problems.push(format!(
+                    "tank {}: bullet {} is not in the bullet catalogue",
+                    name, info.bullet_name
+                ));
+            }
+            let characteristics = &tank.characteristics;
+            if characteristics.bullet_range.is_some_and(|f| f <= 0f32) {
+                problems.push(format!("tank {}: bullet range is not positive", name));
+            }
+            if !(0f32..=90f32).contains(&characteristics.ricochet_angle) {
+                problems.push(format!(
+                    "tank {}: ricochet angle is not between 0 and 90 degrees",
+                    name
+                ));
+            }
+            if characteristics.splash_radius < 0f32
+                || !(0f32..=1f32).contains(&characteristics.splash_falloff)
+            {
+                problems.push(format!(
+                    "tank {}: splash radius is negative or falloff is not between 0 and 1",
+                    name
+                ));
+            }
+            if let Some(ability) = &characteristics.ability {
+                if ability.duration <= 0f32 || ability.cool_down < ability.duration {
+                    problems.push(format!(
+                        "tank {}: ability duration is not positive or longer than its cool down",
+                        name
+                    ));
+                }
+            }
+            if !self.gun_sizes.contains_key(&info.tank_gun_name) {
+                problems.push(format!(
+                    "tank {}: gun {} is not in the gun catalogue",
+                    name, info.tank_gun_name
+                ));
+            }
+        }
+        problems
+    }
+
+    //Tanks at every seat of every mode must not overlap walls, map objects or each other
+    fn spawn_problems(&self, map: &Map, tank_info: &TankInfo, problems: &mut Vec<String>) {
+        for mode in MODES {
+            let players = (0..mode.players_count())
+                .map(|f| {
+                    let tank = Tank {
+                        id: tank_info.id as i32,
+                        level: 1,
+                        count: 0,
+                    };
+                    let player = Player::unregistered(f as i64 + 1, String::new());
+                    WorldPlayer::new(Box::new(player), tank, tank_info, None)
+                })
+                .collect();
+            let battle = Battle::new(players, mode, map, self, 0);
+            let colliders = &battle.world.colliders;
+            let bodies = &battle.world.bodies;
+            //Colliders are placed in the world by the first physics step
+            let position = |collider: &Collider| match collider.parent() {
+                Some(parent) => bodies[parent].position() * collider.position_wrt_parent().unwrap(),
+                None => *collider.position(),
+            };
+            for (index, player) in battle.players.iter().enumerate() {
+                let collider = &colliders[bodies[player.handle].colliders()[0]];
+                let overlaps = colliders.iter().any(|(_, other)| {
+                    other.parent() != Some(player.handle)
+                        && query::intersection_test(
+                            &position(collider),
+                            collider.shape(),
+                            &position(other),
+                            other.shape(),
+                        ) == Ok(true)
+                });
+                if overlaps {
+                    let (x, y, _) = battle.spawn_point(index);
+                    problems.push(format!(
+                        "map {}, {}: tank at spawn point {} ({}, {}) overlaps other bodies",
+                        map.name, mode, index, x, y
+                    ));
+                }
+            }
+        }
+    }
+}
+
+//Object is placed by its top left corner, then scaled and rotated around its center
+fn inside(map: &Map, object: &MapObject, size: Point<Real>) -> bool {
+    let (sin, cos) = object.rotation.to_radians().sin_cos();
+    let scaled = size * object.scale;
+    let half = vector![
+        (scaled.x * cos).abs() + (scaled.y * sin).abs(),
+        (scaled.x * sin).abs() + (scaled.y * cos).abs()
+    ] / 2f32;
+    let center = vector![object.x, object.y] + size.coords / 2f32;
+    center.x - half.x >= 0f32
+        && center.y - half.y >= 0f32
+        && center.x + half.x <= map.width as f32
+        && center.y + half.y <= map.height as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::load_tanks;
+
+    #[test]
+    fn test_bundled_assets_are_consistent() {
+        let assets = Assets::load().unwrap();
+        let tanks = load_tanks("Tanks").unwrap();
+        assert_eq!(assets.problems(&tanks), Vec::<String>::new());
+    }
+}
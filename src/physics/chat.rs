@@ -0,0 +1,102 @@
+use super::{send_packet, Battle, ReplayEvent};
+use crate::data::Packet;
+
+//Player can send a few emotes at once, then one per `EMOTE_INTERVAL` seconds
+pub(super) const EMOTE_BURST: f32 = 3f32;
+const EMOTE_INTERVAL: f32 = 2f32;
+
+impl Battle<'_> {
+    pub(super) fn update_chat(&mut self, step: f32) {
+        for player in self.players.iter_mut() {
+            player.emotes = EMOTE_BURST.min(player.emotes + step / EMOTE_INTERVAL);
+        }
+    }
+
+    //Relayed to other players that did not mute the sender and to spectators.
+    //Emotes missing in the catalogue and emotes over the rate are dropped
+    pub(super) fn send_emote(&mut self, id: i64, emote: u16) {
+        let index = match self.player_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        if !self.assets.emotes.contains(&emote) || self.players[index].emotes < 1f32 {
+            return;
+        }
+        self.record(ReplayEvent::Emote { id, emote });
+        self.players[index].emotes -= 1f32;
+
+        let packet = Packet::Emote {
+            nickname: self.players[index]
+                .player
+                .nickname
+                .clone()
+                .unwrap_or_default(),
+            id: emote,
+        };
+        for (other, player) in self.players.iter().enumerate() {
+            if other != index && !player.muted.contains(&id) {
+                self.send_to(other, &packet);
+            }
+        }
+        for spectator in &self.spectators {
+            send_packet(&spectator.conn, &packet);
+        }
+    }
+
+    //Muted player's emotes are not sent to the player until unmuted
+    pub(super) fn mute(&mut self, id: i64, nickname: &str, muted: bool) {
+        let target = self
+            .players
+            .iter()
+            .find(|f| f.player.nickname.as_deref() == Some(nickname))
+            .map(|f| f.player.id);
+        let (index, target) = match (self.player_index(id), target) {
+            (Some(index), Some(target)) if target != id => (index, target),
+            _ => return,
+        };
+        let list = &mut self.players[index].muted;
+        list.retain(|&f| f != target);
+        if muted {
+            list.push(target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{
+        simulation::{Fixture, TICK_TIME},
+        Replay,
+    };
+
+    #[test]
+    fn test_emotes_are_limited_and_muted() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.replay = Some(Replay::new(1, &sim.battle));
+        let emote = *fixture.assets.emotes.iter().next().unwrap();
+        //Unknown emotes are dropped without using the limit
+        sim.battle.send_emote(1, u16::MAX);
+        for _ in 0..5 {
+            sim.battle.send_emote(1, emote);
+        }
+        let events = &sim.battle.replay.as_ref().unwrap().events;
+        let sent = events
+            .iter()
+            .filter(|f| matches!(f, ReplayEvent::Emote { .. }))
+            .count();
+        assert_eq!(sent, EMOTE_BURST as usize);
+        assert!(sim.battle.players[0].emotes < 1f32);
+        for _ in 0..=(2f32 / TICK_TIME).ceil() as u32 {
+            sim.step();
+        }
+        assert!(sim.battle.players[0].emotes >= 1f32);
+
+        let nickname = fixture.tanks[1].characteristics.name.clone();
+        sim.battle.mute(1, &nickname, true);
+        assert_eq!(sim.battle.players[0].muted, vec![2]);
+        sim.battle.mute(1, &nickname, false);
+        assert!(sim.battle.players[0].muted.is_empty());
+    }
+}
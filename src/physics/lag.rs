@@ -0,0 +1,126 @@
+use rapier2d::{parry::query, prelude::*};
+
+use super::{Battle, BodyType, UserData, SCALE_TO_PIXELS};
+
+//Tank positions and gun angles after a frame
+pub(super) type Snapshot = Vec<(Isometry<Real>, f32)>;
+
+//Clients see the battle `lag` frames late, so shots are checked against
+//the state they actually saw, but not further back than `max_rewind`
+impl Battle<'_> {
+    pub(super) fn lag(&self, index: usize) -> u16 {
+        self.players[index]
+            .ack_frame
+            .map_or(0, |f| self.frame - f)
+            .min(self.max_rewind)
+    }
+
+    //Tank position and gun angle `lag` frames ago, current ones if not stored
+    pub(super) fn rewound_transform(&self, index: usize, lag: u16) -> (Isometry<Real>, f32) {
+        let player = &self.players[index];
+        self.snapshot(lag).map(|f| f[index]).unwrap_or((
+            *self.world.bodies[player.handle].position(),
+            player.stats.gun_angle,
+        ))
+    }
+
+    fn snapshot(&self, lag: u16) -> Option<&Snapshot> {
+        if lag == 0 {
+            return None;
+        }
+        let frame = self.frame - lag;
+        self.history
+            .iter()
+            .rev()
+            .find(|f| f.0 == frame)
+            .map(|f| &f.1)
+    }
+
+    pub(super) fn record_history(&mut self) {
+        if self.max_rewind == 0 {
+            return;
+        }
+        let transforms = self
+            .players
+            .iter()
+            .map(|f| (*self.world.bodies[f.handle].position(), f.stats.gun_angle))
+            .collect();
+        self.history.push_back((self.frame, transforms));
+        while self.history.len() > self.max_rewind as usize + 1 {
+            self.history.pop_front();
+        }
+    }
+
+    //Rewound gun can be behind a wall the tank is standing at now, such bullet
+    //explodes at the wall. Bullets of lagging players are tracked for rewound hits
+    pub(super) fn validate_bullet(&mut self, index: usize, bullet: RigidBodyHandle, lag: u16) {
+        if lag == 0 {
+            return;
+        }
+        self.world.query_pipeline.update(
+            &self.world.islands,
+            &self.world.bodies,
+            &self.world.colliders,
+        );
+        let from = *self.world.bodies[self.players[index].handle].translation();
+        let to = *self.world.bodies[bullet].translation();
+        let distance = (to - from).norm();
+        let blocked = (distance > 0f32)
+            .then(|| {
+                let ray = Ray::new(from.into(), (to - from) / distance);
+                self.world.query_pipeline.cast_ray(
+                    &self.world.bodies,
+                    &self.world.colliders,
+                    &ray,
+                    distance,
+                    true,
+                    QueryFilter::only_fixed().exclude_sensors(),
+                )
+            })
+            .flatten();
+        match blocked {
+            Some((_, toi)) => {
+                self.remove_body(bullet);
+                let point = (from + (to - from) / distance * toi) * SCALE_TO_PIXELS;
+                self.explosion(point.into(), false);
+            }
+            None => self.lagged_bullets.push((bullet, lag)),
+        }
+    }
+
+    //Hits of lagging players' bullets against tanks where the shooter saw them.
+    //Collisions with current positions are still handled by the physics step
+    pub(super) fn rewound_hits(&mut self) {
+        let mut hits = Vec::new();
+        for &(handle, lag) in &self.lagged_bullets {
+            let snapshot = match self.snapshot(lag) {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            let bullet = &self.world.bodies[handle];
+            let shooter: UserData = bullet.user_data.into();
+            let shooter_team = self.player_index(shooter.id).map(|f| self.players[f].team);
+            let bullet_collider = &self.world.colliders[bullet.colliders()[0]];
+            let target = self.players.iter().enumerate().find(|(index, f)| {
+                let collider = &self.world.colliders[self.world.bodies[f.handle].colliders()[0]];
+                let position = snapshot[*index].0 * collider.position_wrt_parent().unwrap();
+                Some(f.team) != shooter_team
+                    && f.stats.hp > 0
+                    && query::intersection_test(
+                        bullet_collider.position(),
+                        bullet_collider.shape(),
+                        &position,
+                        collider.shape(),
+                    ) == Ok(true)
+            });
+            if let Some((_, target)) = target {
+                let data = UserData::new(BodyType::Tank, target.player.id);
+                let point = bullet.translation() * SCALE_TO_PIXELS;
+                hits.push((handle, shooter, target.handle, data, point.into()));
+            }
+        }
+        for (handle, shooter, target, data, point) in hits {
+            self.bullet_collision(handle, shooter, target, data, point);
+        }
+    }
+}
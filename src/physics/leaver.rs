@@ -0,0 +1,101 @@
+use super::{Battle, ReplayEvent};
+
+impl Battle<'_> {
+    //Players whose datagrams can't be delivered forfeit once they are absent
+    //for `reconnect_grace` seconds. Bots have no connection and are never absent
+    pub(super) fn update_absence(&mut self, step: f32) {
+        if self.reconnect_grace <= 0f32 {
+            return;
+        }
+        let mut absent = Vec::new();
+        for player in self.players.iter_mut() {
+            if player.connected || player.conn.is_none() || player.stats.hp == 0 {
+                player.absent = 0f32;
+                continue;
+            }
+            player.absent += step;
+            if player.absent >= self.reconnect_grace {
+                absent.push(player.player.id);
+            }
+        }
+        for id in absent {
+            self.forfeit(id);
+        }
+    }
+
+    pub(super) fn forfeit(&mut self, id: i64) {
+        self.record(ReplayEvent::Forfeit { id });
+        if let Some(index) = self.player_index(id) {
+            if self.players[index].stats.hp > 0 {
+                self.players[index].forfeited = true;
+                self.destroy_tank(index);
+            }
+        }
+    }
+
+    pub(super) fn surrender(&mut self, id: i64) {
+        self.record(ReplayEvent::Surrender { id });
+        if let Some(index) = self.player_index(id) {
+            if self.players[index].stats.hp > 0 {
+                self.players[index].surrendered = true;
+                self.destroy_tank(index);
+            }
+        }
+    }
+
+    //Leaves and surrenders are stored with the players after the battle.
+    //Private matches are arranged among friends, leaving them is not penalised
+    pub(super) fn store_leavers(&mut self) {
+        if self.private.is_some() {
+            return;
+        }
+        for player in self.players.iter_mut().filter(|f| f.bot.is_none()) {
+            if player.forfeited {
+                player.player.add_leave();
+            } else if player.surrendered {
+                player.player.add_surrender();
+            } else {
+                player.player.add_finished_battle();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{data::BattleResult, physics::simulation::Fixture};
+
+    #[test]
+    fn test_surrender_and_forfeit_lose_battle() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.surrender(1);
+        sim.battle.store_leavers();
+        let player = &sim.battle.players[0].player;
+        assert_eq!((player.surrenders, player.leaves), (1, 0));
+        assert_eq!(player.leave_streak, 1);
+        let outcome = sim.run_script(&[]);
+        assert_eq!(outcome.winner, Some(1));
+        assert_eq!(outcome.players[0].result, BattleResult::Defeat);
+
+        //Only the second leave in a row is penalised
+        let mut sim = fixture.duel();
+        sim.battle.forfeit(2);
+        sim.battle.store_leavers();
+        let player = &mut sim.battle.players[1].player;
+        assert_eq!(player.penalty_left(), None);
+        player.add_leave();
+        assert!(player.penalty_left().is_some());
+        assert_eq!(player.leaves, 2);
+        assert_eq!(sim.battle.winner(), Some(0));
+
+        //Surrenders don't wipe the streak out, the next one is penalised too
+        let mut sim = fixture.duel();
+        sim.battle.players[1].player.add_leave();
+        sim.battle.surrender(2);
+        sim.battle.store_leavers();
+        let player = &sim.battle.players[1].player;
+        assert!(player.penalty_left().is_some());
+        assert_eq!(player.leave_streak, 2);
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use minstant::Instant;
+use quinn::Connection;
+use tokio::task::JoinHandle;
+
+use super::{send_packet, BalancedPlayer};
+use crate::data::{BattleMode, Packet, PrivateMatch};
+
+//Players of a finished battle can agree on a rematch for this long
+const LOBBY_TIME: Duration = Duration::from_secs(30);
+
+//Post-battle lobby, rematch starts once every player accepted it. Bots
+//always accept
+pub(super) struct Lobby {
+    pub mode: BattleMode,
+    //In the order of the battle, so players keep their teams and tanks
+    pub players: Vec<BalancedPlayer>,
+    //Rematch of a private match is private too
+    pub private: Option<PrivateMatch>,
+    //Stats of the finished battle are being saved, rematch waits for them
+    pub saved: Option<JoinHandle<()>>,
+    accepted: Vec<i64>,
+    expires: Instant,
+}
+
+impl Lobby {
+    pub fn new(
+        mode: BattleMode,
+        players: Vec<BalancedPlayer>,
+        private: Option<PrivateMatch>,
+    ) -> Self {
+        Self {
+            mode,
+            players,
+            private,
+            saved: None,
+            accepted: Vec::new(),
+            expires: Instant::now() + LOBBY_TIME,
+        }
+    }
+
+    pub fn contains(&self, id: i64) -> bool {
+        self.players.iter().any(|f| f.0.id == id)
+    }
+
+    pub fn ids(&self) -> Vec<i64> {
+        self.players.iter().map(|f| f.0.id).collect()
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    //First acceptance is a request, others are asked to accept it. Returns
+    //true when everybody accepted
+    pub fn accept(&mut self, id: i64, conn: Connection) -> bool {
+        if let Some(player) = self.players.iter_mut().find(|f| f.0.id == id) {
+            //Player could have reconnected after the battle
+            player.2 = Some(conn);
+        }
+        if !self.accepted.contains(&id) {
+            self.accepted.push(id);
+            if self.accepted.len() == 1 {
+                self.notify(id, &Packet::RematchOfferResponse { player_id: id });
+            }
+        }
+        self.players
+            .iter()
+            .all(|f| f.2.is_none() || self.accepted.contains(&f.0.id))
+    }
+
+    pub fn decline(&self, id: i64) {
+        self.notify(id, &Packet::RematchDeclinedResponse);
+    }
+
+    //Everybody except the player with given id
+    fn notify(&self, id: i64, packet: &Packet) {
+        for player in self.players.iter().filter(|f| f.0.id != id) {
+            if let Some(conn) = &player.2 {
+                send_packet(conn, packet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::Player,
+        physics::{loopback, receive},
+    };
+
+    fn player(id: i64, conn: Option<Connection>) -> BalancedPlayer {
+        BalancedPlayer(Box::new(Player::unregistered(id, String::new())), 1, conn)
+    }
+
+    #[test]
+    fn test_rematch_starts_once_everybody_accepted() {
+        let ((first, _), (second, mut client)) = (loopback(), loopback());
+        let players = vec![
+            player(1, Some(first.clone())),
+            player(2, Some(second.clone())),
+            player(3, None),
+        ];
+        let mut lobby = Lobby::new(BattleMode::FreeForAll, players, None);
+        assert!(lobby.contains(3) && !lobby.contains(4));
+        assert_eq!(lobby.ids(), vec![1, 2, 3]);
+        assert!(!lobby.accept(1, first.clone()));
+        assert!(matches!(
+            receive(&mut client),
+            Packet::RematchOfferResponse { player_id: 1 }
+        ));
+        //Accepting twice is not a new request, bots don't have to accept
+        assert!(!lobby.accept(1, first));
+        assert!(lobby.accept(2, second));
+    }
+
+    #[test]
+    fn test_rematch_can_be_declined_until_lobby_expires() {
+        let ((first, _), (second, mut client)) = (loopback(), loopback());
+        let players = vec![player(1, Some(first)), player(2, Some(second))];
+        let mut lobby = Lobby::new(BattleMode::Duel, players, None);
+        lobby.decline(1);
+        assert!(matches!(
+            receive(&mut client),
+            Packet::RematchDeclinedResponse
+        ));
+        assert!(!lobby.expired());
+        lobby.expires = Instant::now();
+        assert!(lobby.expired());
+    }
+}
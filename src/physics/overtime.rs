@@ -0,0 +1,187 @@
+use super::{Battle, SCALE_TO_PIXELS};
+use crate::data::{BattlePhase, Overtime};
+
+//Part of the map size the arena shrinks to by the end of overtime
+const MIN_ARENA: f32 = 0.2f32;
+//Part of max hp tanks outside of the arena lose each second
+const ARENA_DAMAGE: f32 = 0.1f32;
+
+impl Battle<'_> {
+    pub(super) fn set_overtime(&mut self, rule: Overtime, time: f32) {
+        self.overtime = rule;
+        self.overtime_time = time.max(0f32);
+    }
+
+    //Called when time is over and several teams are alive, either starts
+    //overtime or decides the winner right away. Battle is a draw otherwise
+    pub(super) fn time_over(&mut self) {
+        if self.phase == BattlePhase::Overtime {
+            return;
+        }
+        match self.overtime {
+            Overtime::None => {}
+            Overtime::HpTiebreak => self.winner = self.hp_leader(),
+            Overtime::ShrinkingArena | Overtime::NextHit => {
+                if self.overtime_time > 0f32 {
+                    self.phase = BattlePhase::Overtime;
+                    //Tanks can move and shoot only within the battle time
+                    self.overtime_time = self.overtime_time.min(self.battle_time);
+                    self.time = self.overtime_time;
+                    self.arena_time = 0f32;
+                }
+            }
+        }
+    }
+
+    //Team with the highest part of its total max hp left, None on a tie
+    fn hp_leader(&self) -> Option<u8> {
+        let mut teams: Vec<(u8, f32)> = Vec::new();
+        for team in self.alive_teams() {
+            let (hp, max_hp) = self
+                .players
+                .iter()
+                .filter(|f| f.team == team)
+                .fold((0, 0), |acc, f| (acc.0 + f.stats.hp, acc.1 + f.max_hp()));
+            teams.push((team, hp as f32 / max_hp.max(1) as f32));
+        }
+        teams.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+        match teams.as_slice() {
+            [first, second, ..] if first.1 == second.1 => None,
+            [first, ..] => Some(first.0),
+            [] => None,
+        }
+    }
+
+    //Width and height of the arena in pixels, it is centered on the map
+    fn arena_size(&self) -> (f32, f32) {
+        let scale =
+            if self.phase == BattlePhase::Overtime && self.overtime == Overtime::ShrinkingArena {
+                let progress = 1f32 - self.time.max(0f32) / self.overtime_time;
+                1f32 - (1f32 - MIN_ARENA) * progress
+            } else {
+                1f32
+            };
+        (
+            self.map.width as f32 * scale,
+            self.map.height as f32 * scale,
+        )
+    }
+
+    //Tanks outside of the arena take damage once per second, shields don't help
+    pub(super) fn update_overtime(&mut self, step: f32) {
+        if self.phase != BattlePhase::Overtime || self.overtime != Overtime::ShrinkingArena {
+            return;
+        }
+        self.arena_time += step;
+        if self.arena_time < 1f32 {
+            return;
+        }
+        self.arena_time -= 1f32;
+
+        let (width, height) = self.arena_size();
+        let center = (self.map.width as f32 / 2f32, self.map.height as f32 / 2f32);
+        for index in 0..self.players.len() {
+            let player = &self.players[index];
+            if player.stats.hp == 0 {
+                continue;
+            }
+            let position = self.world.bodies[player.handle].translation() * SCALE_TO_PIXELS;
+            if (position.x - center.0).abs() <= width / 2f32
+                && (position.y - center.1).abs() <= height / 2f32
+            {
+                continue;
+            }
+            let damage =
+                ((player.max_hp() as f32 * ARENA_DAMAGE).ceil() as i32).min(player.stats.hp);
+            let player = &mut self.players[index];
+            player.stats.hp -= damage;
+            player.stats.damage_taken += damage;
+            if player.stats.hp == 0 {
+                self.destroy_tank(index);
+            }
+        }
+    }
+
+    //Under the next hit rule the first damaging hit of overtime wins the battle
+    pub(super) fn overtime_hit(&mut self, shooter: usize) {
+        if self.phase == BattlePhase::Overtime
+            && self.overtime == Overtime::NextHit
+            && self.winner.is_none()
+        {
+            self.winner = Some(self.players[shooter].team);
+        }
+    }
+
+    //Team decided by overtime, or the only team with tanks left
+    pub(super) fn winner(&self) -> Option<u8> {
+        if self.winner.is_some() {
+            return self.winner;
+        }
+        let alive_teams = self.alive_teams();
+        if alive_teams.len() == 1 {
+            Some(alive_teams[0])
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{BattleResult, Packet},
+        physics::{
+            loopback, receive,
+            simulation::{Fixture, TICK_TIME},
+            MAX_BATTLE_TIME,
+        },
+    };
+
+    #[test]
+    fn test_hp_tiebreak_decides_battle() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.set_overtime(Overtime::HpTiebreak, 0f32);
+        sim.battle.players[0].stats.hp -= 1;
+        let outcome = sim.run_script(&[]);
+        assert_eq!(outcome.winner, Some(1));
+        assert_eq!(outcome.ticks, (MAX_BATTLE_TIME / TICK_TIME).round() as u32);
+        assert_eq!(outcome.players[0].result, BattleResult::Defeat);
+        assert_eq!(outcome.players[1].result, BattleResult::Victory);
+    }
+
+    #[test]
+    fn test_spectators_learn_tiebreak_winner() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.set_overtime(Overtime::HpTiebreak, 0f32);
+        sim.battle.players[0].stats.hp -= 1;
+        let (conn, mut client) = loopback();
+        sim.battle.add_spectator(1, conn);
+        while !sim.step() {}
+        //Both tanks are alive, spectators still learn who won
+        sim.battle.end_spectating();
+        let winner_team = loop {
+            if let Packet::SpectateEndResponse { winner_team } = receive(&mut client) {
+                break winner_team;
+            }
+        };
+        assert_eq!(winner_team, Some(sim.battle.players[1].team));
+    }
+
+    #[test]
+    fn test_shrinking_arena_damages_tanks_outside() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        sim.battle.set_overtime(Overtime::ShrinkingArena, 30f32);
+        let outcome = sim.run_script(&[]);
+        //Idle tanks at the spawn rows are left outside and lose hp at the same pace
+        assert_eq!(outcome.winner, None);
+        assert!(outcome.ticks > (MAX_BATTLE_TIME / TICK_TIME).round() as u32);
+        for (player, tank) in outcome.players.iter().zip(&fixture.tanks) {
+            assert!(player.hp < tank.characteristics.hp as i32);
+            assert!(player.damage_taken > 0);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use rand::{seq::SliceRandom, Rng};
+use rapier2d::prelude::*;
+
+use super::{Battle, BodyType, UserData, WorldPlayer, SCALE_TO_PHYSICS, SCALE_TO_PIXELS};
+use crate::data::{AbilityKind, ActiveEffect, Packet, PickupKind};
+
+//Seconds between spawns, nothing spawns while `MAX_PICKUPS` are on the map
+const SPAWN_INTERVAL: f32 = 15f32;
+const MAX_PICKUPS: usize = 3;
+//In pixels
+const PICKUP_RADIUS: f32 = 24f32;
+//Random points tried when the map has no pickup points
+const SPAWN_ATTEMPTS: usize = 20;
+const EFFECT_TIME: f32 = 8f32;
+//Part of max hp restored by a repair kit
+const REPAIR: f32 = 0.3f32;
+const RELOAD_BOOST: f32 = 2f32;
+const DAMAGE_BOOST: f32 = 1.5f32;
+const KINDS: [PickupKind; 4] = [
+    PickupKind::Repair,
+    PickupKind::ReloadBoost,
+    PickupKind::DamageBoost,
+    PickupKind::Shield,
+];
+
+pub(super) struct Pickup {
+    id: u32,
+    kind: PickupKind,
+    handle: RigidBodyHandle,
+}
+
+impl WorldPlayer<'_> {
+    //Cool down goes down faster with a reload boost
+    pub(super) fn reload_speed(&self) -> f32 {
+        match self.stats.effect {
+            Some(ActiveEffect {
+                kind: PickupKind::ReloadBoost,
+                ..
+            }) => RELOAD_BOOST,
+            _ => 1f32,
+        }
+    }
+
+    pub(super) fn shielded(&self) -> bool {
+        self.stats
+            .effect
+            .is_some_and(|f| f.kind == PickupKind::Shield)
+            || self.ability_active(AbilityKind::Shield)
+    }
+
+    fn end_effect(&mut self) {
+        if let Some(effect) = self.stats.effect.take() {
+            if effect.kind == PickupKind::DamageBoost {
+                self.stats.damage = self.base_damage();
+            }
+        }
+    }
+
+    //Repair is applied at once, other pickups replace the active effect
+    fn apply_pickup(&mut self, kind: PickupKind) {
+        if kind == PickupKind::Repair {
+            let max_hp = self.max_hp();
+            self.stats.hp = max_hp.min(self.stats.hp + (max_hp as f32 * REPAIR) as i32);
+            return;
+        }
+        self.end_effect();
+        if kind == PickupKind::DamageBoost {
+            self.stats.damage = (self.base_damage() as f32 * DAMAGE_BOOST) as i32;
+        }
+        self.stats.effect = Some(ActiveEffect {
+            kind,
+            time_left: EFFECT_TIME,
+        });
+    }
+}
+
+impl Battle<'_> {
+    pub(super) fn update_pickups(&mut self, step: f32) {
+        for player in self.players.iter_mut() {
+            if let Some(effect) = player.stats.effect.as_mut() {
+                effect.time_left -= step;
+                if effect.time_left <= 0f32 {
+                    player.end_effect();
+                }
+            }
+        }
+        self.collect_pickups();
+
+        self.pickup_time += step;
+        if self.pickup_time >= SPAWN_INTERVAL {
+            self.pickup_time = 0f32;
+            if self.pickups.len() < MAX_PICKUPS {
+                self.spawn_pickup();
+            }
+        }
+    }
+
+    //Pickups are sensors, alive tank that intersects one after the physics step takes it
+    fn collect_pickups(&mut self) {
+        let mut collected = Vec::new();
+        for (position, pickup) in self.pickups.iter().enumerate() {
+            let collider = self.world.bodies[pickup.handle].colliders()[0];
+            let collector = self
+                .world
+                .narrow_phase
+                .intersections_with(collider)
+                .filter(|f| f.2)
+                .find_map(|(collider1, collider2, _)| {
+                    let other = if collider1 == collider {
+                        collider2
+                    } else {
+                        collider1
+                    };
+                    let body = self.world.colliders.get(other)?.parent()?;
+                    let data: UserData = self.world.bodies[body].user_data.into();
+                    (data.body_type == BodyType::Tank).then_some(data.id)
+                })
+                .and_then(|id| self.player_index(id))
+                .filter(|&f| self.players[f].stats.hp > 0);
+            if let Some(index) = collector {
+                collected.push((position, index));
+            }
+        }
+        for (position, index) in collected.into_iter().rev() {
+            let pickup = self.pickups.remove(position);
+            self.remove_body(pickup.handle);
+            self.players[index].apply_pickup(pickup.kind);
+            let player_id = self.players[index].player.id;
+            self.broadcast(&Packet::PickupCollected {
+                id: pickup.id,
+                player_id,
+            });
+        }
+    }
+
+    fn spawn_pickup(&mut self) {
+        let point = match self.free_pickup_point() {
+            Some(point) => point,
+            None => return,
+        };
+        let kind = *KINDS.choose(&mut self.gen).unwrap();
+        let id = self.next_pickup_id;
+        self.next_pickup_id += 1;
+
+        let body = RigidBodyBuilder::fixed()
+            .translation(point)
+            .user_data(UserData::new(BodyType::Pickup, id as i64).into())
+            .build();
+        let collider = ColliderBuilder::ball(PICKUP_RADIUS * SCALE_TO_PHYSICS)
+            .sensor(true)
+            .build();
+        let handle = self.world.bodies.insert(body);
+        self.world
+            .colliders
+            .insert_with_parent(collider, handle, &mut self.world.bodies);
+        self.pickups.push(Pickup { id, kind, handle });
+        let packet = self.pickup_packet(self.pickups.last().unwrap());
+        self.broadcast(&packet);
+    }
+
+    //Map pickup points or random points of the map, not covered by any collider
+    fn free_pickup_point(&mut self) -> Option<Vector<Real>> {
+        let candidates: Vec<Vector<Real>> = if self.map.pickup_points.is_empty() {
+            let margin = PICKUP_RADIUS * 2f32;
+            let (width, height) = (self.map.width as f32, self.map.height as f32);
+            if width <= margin * 2f32 || height <= margin * 2f32 {
+                return None;
+            }
+            (0..SPAWN_ATTEMPTS)
+                .map(|_| {
+                    vector![
+                        self.gen.gen_range(margin..width - margin),
+                        self.gen.gen_range(margin..height - margin)
+                    ]
+                })
+                .collect()
+        } else {
+            let mut points: Vec<Vector<Real>> = self
+                .map
+                .pickup_points
+                .iter()
+                .map(|f| vector![f.x, f.y])
+                .collect();
+            points.shuffle(&mut self.gen);
+            points
+        };
+
+        self.world.query_pipeline.update(
+            &self.world.islands,
+            &self.world.bodies,
+            &self.world.colliders,
+        );
+        let shape = Ball::new(PICKUP_RADIUS * SCALE_TO_PHYSICS);
+        candidates
+            .into_iter()
+            .map(|f| f * SCALE_TO_PHYSICS)
+            .find(|point| {
+                self.world
+                    .query_pipeline
+                    .intersection_with_shape(
+                        &self.world.bodies,
+                        &self.world.colliders,
+                        &Isometry::translation(point.x, point.y),
+                        &shape,
+                        QueryFilter::default(),
+                    )
+                    .is_none()
+            })
+    }
+
+    fn pickup_packet(&self, pickup: &Pickup) -> Packet {
+        let position = self.world.bodies[pickup.handle].translation() * SCALE_TO_PIXELS;
+        Packet::PickupSpawned {
+            id: pickup.id,
+            kind: pickup.kind,
+            x: position.x,
+            y: position.y,
+        }
+    }
+
+    //For players and spectators who join the battle after pickups were spawned
+    pub(super) fn pickup_packets(&self) -> Vec<Packet> {
+        self.pickups.iter().map(|f| self.pickup_packet(f)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::simulation::{Fixture, TICK_TIME};
+
+    #[test]
+    fn test_pickup_effects_expire() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        let damage = sim.battle.players[0].stats.damage;
+        sim.battle.players[0].apply_pickup(PickupKind::DamageBoost);
+        sim.battle.players[1].apply_pickup(PickupKind::Shield);
+        assert!(sim.battle.players[0].stats.damage > damage);
+        assert!(sim.battle.players[1].shielded());
+        //New effect replaces the active one
+        sim.battle.players[0].apply_pickup(PickupKind::ReloadBoost);
+        assert_eq!(sim.battle.players[0].stats.damage, damage);
+        assert_eq!(sim.battle.players[0].reload_speed(), RELOAD_BOOST);
+
+        for _ in 0..=(EFFECT_TIME / TICK_TIME).ceil() as u32 {
+            sim.step();
+        }
+        for player in &sim.battle.players {
+            assert_eq!(player.stats.effect, None);
+            assert_eq!(player.reload_speed(), 1f32);
+            assert!(!player.shielded());
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use quinn::Connection;
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{send_packet, Assets, Battle, Result, WorldPlayer, ASSETS, MAX_BATTLE_TIME, WAIT_TIME};
+use crate::data::{
+    BattleMode, BattleResult, BattleResultStruct, Map, Overtime, Packet, Player, PlayerPosition,
+    Tank, TankInfo, CONFIG,
+};
+
+const REPLAY_VERSION: u16 = 2;
+//Replays streamed at the same time, each one takes a thread
+const MAX_STREAMS: usize = 16;
+
+//Stream number by viewer, a newer request of the viewer stops the older stream
+static STREAMS: Mutex<BTreeMap<i64, u64>> = Mutex::new(BTreeMap::new());
+static STREAM_NUMBER: AtomicU64 = AtomicU64::new(0);
+
+//Everything needed to simulate the battle again. Tanks and map are copied,
+//so replays stay valid after assets are changed
+#[derive(Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u16,
+    pub id: i64,
+    pub map: Map,
+    pub mode: BattleMode,
+    pub players: Vec<ReplayPlayer>,
+    pub events: Vec<ReplayEvent>,
+    #[serde(default)]
+    pub max_rewind: u16,
+    pub seed: u64,
+    #[serde(default)]
+    pub overtime: Overtime,
+    #[serde(default)]
+    pub overtime_time: f32,
+    #[serde(default = "default_battle_time")]
+    pub battle_time: f32,
+    //Replays of private matches are watched only by their players
+    #[serde(default)]
+    pub private: bool,
+}
+
+fn default_battle_time() -> f32 {
+    MAX_BATTLE_TIME
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplayPlayer {
+    pub id: i64,
+    pub nickname: String,
+    pub team: u8,
+    pub tank: Tank,
+    pub tank_info: TankInfo,
+}
+
+//Events are stored in order they happened, `Tick` separates simulation steps
+#[derive(Serialize, Deserialize)]
+pub enum ReplayEvent {
+    Tick { dt: f32 },
+    Position { id: i64, position: PlayerPosition },
+    Shoot { id: i64 },
+    Ability { id: i64 },
+    Kick { id: i64 },
+    Surrender { id: i64 },
+    Forfeit { id: i64 },
+    Emote { id: i64, emote: u16 },
+    Explosion { x: f32, y: f32, hit: bool },
+    Result { id: i64, result: BattleResultStruct },
+    ObjectDestroyed { index: u32 },
+}
+
+impl Replay {
+    pub(super) fn new(id: i64, battle: &Battle) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            id,
+            map: battle.map.clone(),
+            mode: battle.mode,
+            players: battle
+                .players
+                .iter()
+                .map(|f| ReplayPlayer {
+                    id: f.player.id,
+                    nickname: f.player.nickname.clone().unwrap_or_default(),
+                    team: f.team,
+                    tank: f.tank.clone(),
+                    tank_info: f.tank_info.clone(),
+                })
+                .collect(),
+            events: Vec::new(),
+            max_rewind: battle.max_rewind,
+            seed: battle.seed,
+            overtime: battle.overtime,
+            overtime_time: battle.overtime_time,
+            battle_time: battle.battle_time,
+            private: battle.private.is_some(),
+        }
+    }
+
+    fn can_watch(&self, viewer_id: i64) -> bool {
+        !self.private || self.players.iter().any(|f| f.id == viewer_id)
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let mut buf = Vec::new();
+        let mut serializer = Serializer::new(&mut buf);
+        self.serialize(&mut serializer)?;
+        std::fs::write(dir.join(format!("{}.replay", self.id)), buf)?;
+        Ok(())
+    }
+
+    pub fn load(dir: &Path, id: i64) -> Result<Self> {
+        let data = std::fs::read(dir.join(format!("{}.replay", id)))?;
+        let replay = Self::deserialize(&mut Deserializer::new(data.as_slice()))?;
+        if replay.version != REPLAY_VERSION {
+            color_eyre::eyre::bail!("unsupported replay version {}", replay.version);
+        }
+        Ok(replay)
+    }
+
+    //Simulates the battle again and streams it to the viewer in real time,
+    //until the viewer asks for another replay
+    fn play(&self, viewer_id: i64, conn: Connection, number: u64) {
+        self.stream(ASSETS.get(), viewer_id, conn, |dt| {
+            std::thread::sleep(dt);
+            STREAMS.lock().unwrap().get(&viewer_id) == Some(&number)
+        });
+    }
+
+    //Battle as it was when the recording started
+    fn battle<'a>(&'a self, assets: &'a Assets) -> Battle<'a> {
+        let players = self
+            .players
+            .iter()
+            .map(|f| {
+                WorldPlayer::new(
+                    Box::new(Player::unregistered(f.id, f.nickname.clone())),
+                    f.tank.clone(),
+                    &f.tank_info,
+                    None,
+                )
+            })
+            .collect();
+        let mut battle = Battle::new(players, self.mode, &self.map, assets, self.seed);
+        battle.max_rewind = self.max_rewind;
+        battle.set_overtime(self.overtime, self.overtime_time);
+        battle.set_battle_time(self.battle_time);
+        battle
+    }
+
+    //Viewer takes the seat of its own tank, or the first one if it did not take
+    //part. `sleep` is called with the time of every tick before it is simulated,
+    //streaming stops when it returns false
+    fn stream<F: Fn(Duration) -> bool>(
+        &self,
+        assets: &Assets,
+        viewer_id: i64,
+        conn: Connection,
+        sleep: F,
+    ) {
+        let mut battle = self.battle(assets);
+        let seat = battle.player_index(viewer_id).unwrap_or(0);
+        battle.players[seat].conn = Some(conn.clone());
+        battle.players[seat].connected = true;
+        send_packet(&conn, &battle.map_found_packet(seat, WAIT_TIME));
+
+        for event in &self.events {
+            //Stop streaming once datagrams can't be delivered to the viewer
+            if !battle.players[seat].connected {
+                return;
+            }
+            if let ReplayEvent::Tick { dt } = event {
+                if !sleep(Duration::from_secs_f32(*dt)) {
+                    return;
+                }
+            }
+            if apply(&mut battle, event) {
+                break;
+            }
+        }
+
+        //Simulation must end with the same winner as the recorded battle
+        let winner = battle.winner();
+        let diverged = self.events.iter().any(|f| match f {
+            ReplayEvent::Result { id, result } => {
+                let team = battle.player_index(*id).map(|f| battle.players[f].team);
+                (result.result == BattleResult::Victory) != (winner.is_some() && team == winner)
+            }
+            _ => false,
+        });
+        if diverged {
+            warn!("replay {} diverged from the recorded battle", self.id);
+        }
+
+        let result = self.events.iter().find_map(|f| match f {
+            ReplayEvent::Result { id, result } if *id == battle.players[seat].player.id => {
+                Some(result.clone())
+            }
+            _ => None,
+        });
+        send_packet(&conn, &Packet::ReplayEndResponse { result });
+    }
+}
+
+//Returns true when the battle is over
+fn apply(battle: &mut Battle, event: &ReplayEvent) -> bool {
+    match event {
+        ReplayEvent::Tick { dt } => return battle.update(*dt),
+        ReplayEvent::Position { id, position } => battle.move_player(*id, position.clone()),
+        ReplayEvent::Shoot { id } => battle.shoot(*id),
+        ReplayEvent::Ability { id } => battle.use_ability(*id),
+        ReplayEvent::Kick { id } => battle.kick(*id),
+        ReplayEvent::Surrender { id } => battle.surrender(*id),
+        ReplayEvent::Forfeit { id } => battle.forfeit(*id),
+        ReplayEvent::Emote { id, emote } => battle.send_emote(*id, *emote),
+        ReplayEvent::Explosion { .. }
+        | ReplayEvent::ObjectDestroyed { .. }
+        | ReplayEvent::Result { .. } => {}
+    }
+    false
+}
+
+//Viewer watches one replay at a time. When all streams are taken, or the
+//viewer can't watch the replay, it is not found
+pub fn play_replay(id: i64, viewer_id: i64, conn: Connection) {
+    let number = {
+        let mut streams = STREAMS.lock().unwrap();
+        if streams.len() >= MAX_STREAMS && !streams.contains_key(&viewer_id) {
+            send_packet(&conn, &Packet::ReplayNotFoundResponse);
+            return;
+        }
+        let number = STREAM_NUMBER.fetch_add(1, Ordering::Relaxed);
+        streams.insert(viewer_id, number);
+        number
+    };
+    std::thread::spawn(move || {
+        let replay = match &CONFIG.get().replays_dir {
+            Some(dir) => Replay::load(dir, id),
+            None => Err(color_eyre::eyre::eyre!("replays are disabled")),
+        };
+        match replay {
+            Ok(replay) if replay.can_watch(viewer_id) => replay.play(viewer_id, conn, number),
+            Ok(_) => send_packet(&conn, &Packet::ReplayNotFoundResponse),
+            Err(e) => {
+                warn!("failed to load replay {}: {}", id, e);
+                send_packet(&conn, &Packet::ReplayNotFoundResponse);
+            }
+        }
+        let mut streams = STREAMS.lock().unwrap();
+        if streams.get(&viewer_id) == Some(&number) {
+            streams.remove(&viewer_id);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        data::{SnapshotPacket, RUNTIME},
+        physics::{loopback, receive, simulation::Fixture, Bot},
+    };
+
+    //Duel of bots, recorded like a live battle
+    fn record(fixture: &Fixture, battle_time: f32) -> Replay {
+        let mut sim = fixture.duel();
+        //Live battles start with players loading the map
+        sim.battle.time += WAIT_TIME;
+        sim.battle.set_battle_time(battle_time);
+        sim.battle.players[0].bot = Some(Bot::new(500));
+        sim.battle.players[1].bot = Some(Bot::new(2000));
+        sim.battle.replay = Some(Replay::new(1, &sim.battle));
+        while !sim.step() {}
+        sim.battle.send_results(&mut sim.gen);
+        sim.battle.replay.take().unwrap()
+    }
+
+    //Tick of the explosion, its position and whether it was a hit
+    fn explosions(replay: &Replay) -> Vec<(usize, f32, f32, bool)> {
+        let mut tick = 0;
+        let mut explosions = Vec::new();
+        for event in &replay.events {
+            match event {
+                ReplayEvent::Tick { .. } => tick += 1,
+                ReplayEvent::Explosion { x, y, hit } => explosions.push((tick, *x, *y, *hit)),
+                _ => {}
+            }
+        }
+        explosions
+    }
+
+    #[test]
+    fn test_bot_battle_is_replayed_the_same_way() {
+        let fixture = Fixture::load();
+        let replay = record(&fixture, 20f32);
+        let recorded = explosions(&replay);
+        assert!(!recorded.is_empty());
+        //Replayed battle records the inputs of the bot as if they came from a client
+        let mut battle = replay.battle(&fixture.assets);
+        battle.replay = Some(Replay::new(1, &battle));
+        for event in &replay.events {
+            if apply(&mut battle, event) {
+                break;
+            }
+        }
+        assert_eq!(explosions(battle.replay.as_ref().unwrap()), recorded);
+    }
+
+    #[test]
+    fn test_replay_is_streamed_to_viewer() {
+        let fixture = Fixture::load();
+        let replay = record(&fixture, 2f32);
+
+        let (conn, mut client) = loopback();
+        replay.stream(&fixture.assets, 1, conn, |_| true);
+        let mut packets = Vec::new();
+        loop {
+            match receive(&mut client) {
+                Packet::ReplayEndResponse { result } => {
+                    assert!(result.is_some());
+                    break;
+                }
+                packet => packets.push(packet),
+            }
+        }
+        assert!(packets
+            .iter()
+            .any(|f| matches!(f, Packet::MapFoundResponse { .. })));
+        let datagram = RUNTIME.get().block_on(client.datagrams.next());
+        let buf = datagram.unwrap().unwrap();
+        SnapshotPacket::deserialize(&mut Deserializer::new(buf.as_ref())).unwrap();
+    }
+
+    #[test]
+    fn test_private_replay_is_watched_only_by_players() {
+        let fixture = Fixture::load();
+        let mut replay = record(&fixture, 2f32);
+        assert!(replay.can_watch(1) && replay.can_watch(3));
+        replay.private = true;
+        assert!(replay.can_watch(1) && replay.can_watch(2));
+        assert!(!replay.can_watch(3));
+    }
+
+    #[test]
+    fn test_replay_stream_can_be_stopped() {
+        let fixture = Fixture::load();
+        let replay = record(&fixture, 2f32);
+
+        let (conn, mut client) = loopback();
+        replay.stream(&fixture.assets, 1, conn, |_| false);
+        assert!(matches!(
+            receive(&mut client),
+            Packet::MapFoundResponse { .. }
+        ));
+        let next = RUNTIME.get().block_on(async {
+            let timeout = Duration::from_millis(500);
+            tokio::time::timeout(timeout, client.uni_streams.next()).await
+        });
+        //Stopped stream doesn't send the end of the replay
+        assert!(!matches!(next, Ok(Some(Ok(_)))));
+    }
+}
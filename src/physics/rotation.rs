@@ -0,0 +1,117 @@
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+
+use super::WorldPlayer;
+use crate::data::{BattleMode, Map};
+
+//Enabled map for the mode, open to trophies of every player. Bots don't limit maps
+fn eligible(map: &Map, mode: BattleMode, players: &[WorldPlayer]) -> bool {
+    let selection = &map.selection;
+    selection.enabled
+        && selection.weight > 0f32
+        && supports(map, mode)
+        && players.iter().filter(|f| f.bot.is_none()).all(|f| {
+            f.player.trophies >= selection.min_trophies
+                && selection
+                    .max_trophies
+                    .is_none_or(|max| f.player.trophies <= max)
+        })
+}
+
+fn supports(map: &Map, mode: BattleMode) -> bool {
+    map.selection.modes.is_empty() || map.selection.modes.contains(&mode)
+}
+
+//Weighted random eligible map, last maps of the players are played only when
+//there is nothing else. Without eligible maps trophy limits are ignored, so
+//the matched players are not sent back
+pub(super) fn choose_map<'a>(
+    maps: &'a [Map],
+    mode: BattleMode,
+    players: &[WorldPlayer],
+    gen: &mut impl Rng,
+) -> &'a Map {
+    let eligible: Vec<&Map> = maps.iter().filter(|f| eligible(f, mode, players)).collect();
+    let fresh: Vec<&Map> = eligible
+        .iter()
+        .copied()
+        .filter(|map| {
+            !players
+                .iter()
+                .any(|f| f.player.last_map.as_ref() == Some(&map.name))
+        })
+        .collect();
+    let candidates = if !fresh.is_empty() { fresh } else { eligible };
+    match WeightedIndex::new(candidates.iter().map(|f| f.selection.weight)) {
+        Ok(weights) => candidates[weights.sample(gen)],
+        Err(_) => {
+            let enabled: Vec<&Map> = maps
+                .iter()
+                .filter(|f| f.selection.enabled && supports(f, mode))
+                .collect();
+            if enabled.is_empty() {
+                &maps[gen.gen_range(0..maps.len())]
+            } else {
+                enabled[gen.gen_range(0..enabled.len())]
+            }
+        }
+    }
+}
+
+//Private matches can be played on any map that supports the mode
+pub(super) fn find_map<'a>(maps: &'a [Map], name: &str, mode: BattleMode) -> Option<&'a Map> {
+    maps.iter().find(|f| f.name == name && supports(f, mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        data::{Player, Tank},
+        physics::{load_maps, load_tanks},
+    };
+
+    #[test]
+    fn test_choose_map_skips_gated_and_last_maps() {
+        let tanks = load_tanks("Tanks").unwrap();
+        let base = load_maps("Maps").unwrap().remove(0);
+        let mut maps: Vec<Map> = ["Open", "Gated", "Last", "Disabled"]
+            .into_iter()
+            .map(|name| Map {
+                name: name.to_string(),
+                ..base.clone()
+            })
+            .collect();
+        maps[1].selection.min_trophies = 1000;
+        maps[3].selection.enabled = false;
+        let players: Vec<WorldPlayer> = (0..2)
+            .map(|f| {
+                let mut player = Player::unregistered(f + 1, String::new());
+                player.last_map = Some("Last".to_string());
+                let tank = Tank {
+                    id: tanks[0].id as i32,
+                    level: 1,
+                    count: 0,
+                };
+                WorldPlayer::new(Box::new(player), tank, &tanks[0], None)
+            })
+            .collect();
+
+        let mut gen = StdRng::seed_from_u64(0);
+        for _ in 0..50 {
+            let map = choose_map(&maps, BattleMode::Duel, &players, &mut gen);
+            assert_eq!(map.name, "Open");
+        }
+        //Last map is played again rather than a map players can't get
+        maps[0].selection.modes = vec![BattleMode::TwoVsTwo];
+        let map = choose_map(&maps, BattleMode::Duel, &players, &mut gen);
+        assert_eq!(map.name, "Last");
+        //Without eligible maps, the gated one is played but never a disabled one
+        maps[2].selection.modes = vec![BattleMode::TwoVsTwo];
+        for _ in 0..50 {
+            let map = choose_map(&maps, BattleMode::Duel, &players, &mut gen);
+            assert_eq!(map.name, "Gated");
+        }
+    }
+}
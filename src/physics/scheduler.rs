@@ -0,0 +1,624 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use flume::{Receiver, RecvTimeoutError, Selector, Sender};
+use minstant::Instant;
+use rand::{rngs::ThreadRng, Rng};
+use tracing::{error, info};
+
+use super::{
+    lobby::Lobby, rotation, send_packet, BalancedPlayer, Battle, PhysicsCommand, Replay,
+    WorldPlayer, ASSETS, WAIT_TIME,
+};
+use crate::data::{BalancerCommand, Packet, Player, CONFIG, MATCHMAKER, PHYSICS, RUNTIME};
+
+const LOAD_LOG_INTERVAL: Duration = Duration::from_secs(60);
+//Busy time is measured over this period
+const LOAD_PERIOD: Duration = Duration::from_secs(1);
+
+//Shared between the worker and the router. Router counts battles and players
+//as soon as it assigns a match, so a burst of matches is spread over workers
+#[derive(Default)]
+struct Load {
+    battles: AtomicUsize,
+    players: AtomicUsize,
+    //Per mille of `LOAD_PERIOD` spent on updating battles
+    busy: AtomicU32,
+    //Highest sum of trophies of a battle, `i64::MIN` without battles
+    featured: AtomicI64,
+}
+
+#[derive(Debug)]
+pub struct WorkerLoad {
+    pub battles: usize,
+    pub players: usize,
+    pub busy: f32,
+}
+
+static LOADS: state::Storage<Vec<Arc<Load>>> = state::Storage::new();
+//Ids of players in battles, the router keeps it in sync with its owners
+static IN_BATTLE: state::Storage<dashmap::DashSet<i64>> = state::Storage::new();
+
+//Current load of every worker, empty until physics is started
+pub fn load() -> Vec<WorkerLoad> {
+    LOADS
+        .try_get()
+        .map(|loads| {
+            loads
+                .iter()
+                .map(|f| WorkerLoad {
+                    battles: f.battles.load(Ordering::Relaxed),
+                    players: f.players.load(Ordering::Relaxed),
+                    busy: f.busy.load(Ordering::Relaxed) as f32 / 1000f32,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn in_battle(id: i64) -> bool {
+    IN_BATTLE.try_get().is_some_and(|f| f.contains(&id))
+}
+
+//Battles are sharded over `workers` threads. Every command goes through the
+//router to the worker that owns the battle of the player
+pub(super) fn start(workers: usize) -> Sender<PhysicsCommand> {
+    let (send, recv) = flume::unbounded();
+    let (finished_send, finished_recv) = flume::unbounded();
+    let mut senders = Vec::new();
+    let mut loads = Vec::new();
+    for index in 0..workers.max(1) {
+        let (worker_send, worker_recv) = flume::unbounded();
+        let load = Arc::new(Load {
+            featured: AtomicI64::new(i64::MIN),
+            ..Default::default()
+        });
+        let (worker_load, finished) = (load.clone(), finished_send.clone());
+        std::thread::spawn(move || {
+            let worker = Worker {
+                index,
+                battles: Vec::new(),
+                map: HashMap::new(),
+                lobbies: Vec::new(),
+                load: worker_load,
+                finished,
+                gen: rand::thread_rng(),
+                busy: Duration::ZERO,
+                period: Instant::now(),
+            };
+            worker.run(worker_recv)
+        });
+        senders.push(worker_send);
+        loads.push(load);
+    }
+    LOADS.set(loads.clone());
+    IN_BATTLE.set(dashmap::DashSet::new());
+
+    let router = Router {
+        workers: senders,
+        loads,
+        owners: HashMap::new(),
+        lobbies: HashMap::new(),
+    };
+    std::thread::spawn(move || router.run(recv, finished_recv));
+    send
+}
+
+enum Message {
+    Command(PhysicsCommand),
+    Finished(Finished),
+    Disconnected,
+}
+
+//Sent by workers, so the router knows who is in a battle or a lobby
+enum Finished {
+    //Players of a battle that is over or was not created
+    Battle(Vec<i64>),
+    //Players of a lobby that expired, was declined or accepted on the worker
+    Lobby(usize, Vec<i64>),
+}
+
+struct Router {
+    workers: Vec<Sender<PhysicsCommand>>,
+    loads: Vec<Arc<Load>>,
+    //Worker by id of a player in battle
+    owners: HashMap<i64, usize>,
+    //Worker that ran the last battle of a player, it keeps the post-battle lobby
+    lobbies: HashMap<i64, usize>,
+}
+
+impl Router {
+    fn run(mut self, recv: Receiver<PhysicsCommand>, finished: Receiver<Finished>) {
+        let mut last_log = Instant::now();
+        loop {
+            let message = Selector::new()
+                .recv(&recv, |f| f.map_or(Message::Disconnected, Message::Command))
+                .recv(&finished, |f| {
+                    f.map_or(Message::Disconnected, Message::Finished)
+                })
+                .wait_timeout(LOAD_LOG_INTERVAL);
+            match message {
+                Ok(Message::Command(cmd)) => self.route(cmd),
+                Ok(Message::Finished(Finished::Battle(players))) => {
+                    for id in players {
+                        if let Some(worker) = self.owners.remove(&id) {
+                            self.lobbies.insert(id, worker);
+                        }
+                        IN_BATTLE.get().remove(&id);
+                    }
+                }
+                Ok(Message::Finished(Finished::Lobby(worker, players))) => {
+                    //Player could have finished another battle on another worker since
+                    for id in players {
+                        if self.lobbies.get(&id) == Some(&worker) {
+                            self.lobbies.remove(&id);
+                        }
+                    }
+                }
+                Ok(Message::Disconnected) => break,
+                Err(_) => {}
+            }
+            if last_log.elapsed() >= LOAD_LOG_INTERVAL {
+                last_log = Instant::now();
+                for (index, load) in load().iter().enumerate() {
+                    info!(
+                        "physics worker {}: {} battles, {} players, {:.0}% busy",
+                        index,
+                        load.battles,
+                        load.players,
+                        load.busy * 100f32
+                    );
+                }
+            }
+        }
+    }
+
+    fn send(&self, worker: usize, cmd: PhysicsCommand) {
+        if self.workers[worker].send(cmd).is_err() {
+            error!("physics worker {} has stopped", worker);
+        }
+    }
+
+    fn route(&mut self, cmd: PhysicsCommand) {
+        match cmd {
+            PhysicsCommand::CreateMatch {
+                players,
+                mode,
+                private,
+            } => {
+                //Players that are free are told the match is off, bots are
+                //dropped with it
+                if players.len() != mode.players_count()
+                    || players.iter().any(|f| self.owners.contains_key(&f.0.id))
+                {
+                    for player in &players {
+                        if let (Some(conn), false) =
+                            (&player.2, self.owners.contains_key(&player.0.id))
+                        {
+                            send_packet(conn, &Packet::MapNotFoundResponse);
+                        }
+                    }
+                    return;
+                }
+                let worker = (0..self.loads.len())
+                    .min_by_key(|&f| self.loads[f].players.load(Ordering::Relaxed))
+                    .unwrap();
+                self.loads[worker].battles.fetch_add(1, Ordering::Relaxed);
+                self.loads[worker]
+                    .players
+                    .fetch_add(players.len(), Ordering::Relaxed);
+                for player in &players {
+                    self.owners.insert(player.0.id, worker);
+                    IN_BATTLE.get().insert(player.0.id);
+                }
+                self.send(
+                    worker,
+                    PhysicsCommand::CreateMatch {
+                        players,
+                        mode,
+                        private,
+                    },
+                );
+            }
+            PhysicsCommand::PlayerPacket { id, .. }
+            | PhysicsCommand::PlayerShoot { id }
+            | PhysicsCommand::PlayerAbility { id }
+            | PhysicsCommand::PlayerSurrender { id }
+            | PhysicsCommand::PlayerEmote { id, .. }
+            | PhysicsCommand::PlayerMute { id, .. } => {
+                if let Some(&worker) = self.owners.get(&id) {
+                    self.send(worker, cmd);
+                }
+            }
+            PhysicsCommand::Rematch { id, .. } => {
+                if let Some(&worker) = self.lobbies.get(&id) {
+                    self.send(worker, cmd);
+                }
+            }
+            PhysicsCommand::NotifyPlayerAboutMatch { id, new_conn } => match self.owners.get(&id) {
+                Some(&worker) => self.send(
+                    worker,
+                    PhysicsCommand::NotifyPlayerAboutMatch { id, new_conn },
+                ),
+                None => send_packet(&new_conn, &Packet::MapNotFoundResponse),
+            },
+            PhysicsCommand::AddSpectator { id, target, conn } => {
+                //Spectator watches one battle at a time
+                for worker in 0..self.workers.len() {
+                    self.send(worker, PhysicsCommand::RemoveSpectator { id });
+                }
+                //Without target the worker with the featured battle chooses it
+                let worker = match target {
+                    Some(target) => self.owners.get(&target).copied(),
+                    None => (0..self.loads.len())
+                        .filter(|&f| self.loads[f].featured.load(Ordering::Relaxed) != i64::MIN)
+                        .max_by_key(|&f| self.loads[f].featured.load(Ordering::Relaxed)),
+                };
+                match worker {
+                    Some(worker) => {
+                        self.send(worker, PhysicsCommand::AddSpectator { id, target, conn })
+                    }
+                    None => send_packet(&conn, &Packet::SpectateNotFoundResponse),
+                }
+            }
+            PhysicsCommand::RemoveSpectator { id } => {
+                for worker in 0..self.workers.len() {
+                    self.send(worker, PhysicsCommand::RemoveSpectator { id });
+                }
+            }
+        }
+    }
+}
+
+struct Worker {
+    index: usize,
+    battles: Vec<Battle<'static>>,
+    //Index of the battle by id of a player in it
+    map: HashMap<i64, usize>,
+    lobbies: Vec<Lobby>,
+    load: Arc<Load>,
+    finished: Sender<Finished>,
+    gen: ThreadRng,
+    busy: Duration,
+    period: Instant,
+}
+
+impl Worker {
+    //Sleeps until a command arrives or the next battle has to be updated
+    fn run(mut self, recv: Receiver<PhysicsCommand>) {
+        loop {
+            let next_tick = self
+                .battles
+                .iter()
+                .map(|f| f.next_tick())
+                .min_by(f32::total_cmp);
+            let cmd = match next_tick {
+                None => recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(wait) => recv.recv_timeout(Duration::from_secs_f32(wait.max(0f32))),
+            };
+            match cmd {
+                Ok(cmd) => {
+                    self.handle(cmd);
+                    for cmd in recv.try_iter() {
+                        self.handle(cmd);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.update();
+        }
+    }
+
+    fn handle(&mut self, cmd: PhysicsCommand) {
+        let assets = ASSETS.get();
+        match cmd {
+            PhysicsCommand::CreateMatch {
+                players,
+                mode,
+                private,
+            } => {
+                let ids: Vec<i64> = players.iter().map(|f| f.0.id).collect();
+                let players: Result<Vec<WorldPlayer>, _> =
+                    players.into_iter().map(WorldPlayer::try_from).collect();
+                let players = match players {
+                    Ok(players) => players,
+                    Err(_) => {
+                        self.load.battles.fetch_sub(1, Ordering::Relaxed);
+                        self.load.players.fetch_sub(ids.len(), Ordering::Relaxed);
+                        let _ = self.finished.send(Finished::Battle(ids));
+                        return;
+                    }
+                };
+                let chosen = private
+                    .as_ref()
+                    .and_then(|f| f.map.as_ref())
+                    .and_then(|f| rotation::find_map(&assets.maps, f, mode));
+                let battle_map = match chosen {
+                    Some(map) => map,
+                    None => rotation::choose_map(&assets.maps, mode, &players, &mut self.gen),
+                };
+                let mut battle = Battle::new(players, mode, battle_map, assets, self.gen.gen());
+                match &private {
+                    Some(private) => {
+                        if let Some(time) = private.battle_time {
+                            battle.set_battle_time(time as f32);
+                        }
+                    }
+                    None => {
+                        for player in battle.players.iter_mut().filter(|f| f.bot.is_none()) {
+                            player.player.last_map = Some(battle_map.name.clone());
+                        }
+                    }
+                }
+                battle.private = private;
+                let config = CONFIG.get();
+                battle.set_rates(config.tick_rate, config.send_rate);
+                battle.set_overtime(config.overtime, config.overtime_time.as_secs_f32());
+                battle.reconnect_grace = config.reconnect_grace.as_secs_f32();
+                battle.max_rewind = (config.max_rewind.as_secs_f32() / battle.tick_time) as u16;
+                if config.replays_dir.is_some() {
+                    let id = crate::db::ID_GEN.get().lock().real_time_generate();
+                    battle.replay = Some(Replay::new(id, &battle));
+                }
+
+                //notify players
+                for index in 0..battle.players.len() {
+                    let data = battle.map_found_packet(index, WAIT_TIME);
+                    battle.send_to(index, &data);
+                }
+
+                for player in &battle.players {
+                    self.map.insert(player.player.id, self.battles.len());
+                }
+                self.battles.push(battle);
+                self.update_featured();
+            }
+            PhysicsCommand::PlayerPacket { id, position } => {
+                if let Some(&index) = self.map.get(&id) {
+                    let battle = &mut self.battles[index];
+                    if battle.check_position(id, &position) {
+                        battle.move_player(id, position);
+                    }
+                }
+            }
+            PhysicsCommand::PlayerShoot { id } => {
+                if let Some(&index) = self.map.get(&id) {
+                    let battle = &mut self.battles[index];
+                    if battle.check_action(id) {
+                        battle.shoot(id);
+                    }
+                }
+            }
+            PhysicsCommand::PlayerAbility { id } => {
+                if let Some(&index) = self.map.get(&id) {
+                    let battle = &mut self.battles[index];
+                    if battle.check_action(id) {
+                        battle.use_ability(id);
+                    }
+                }
+            }
+            PhysicsCommand::PlayerSurrender { id } => {
+                if let Some(&index) = self.map.get(&id) {
+                    self.battles[index].surrender(id);
+                }
+            }
+            PhysicsCommand::PlayerEmote { id, emote } => {
+                if let Some(&index) = self.map.get(&id) {
+                    self.battles[index].send_emote(id, emote);
+                }
+            }
+            PhysicsCommand::PlayerMute {
+                id,
+                nickname,
+                muted,
+            } => {
+                if let Some(&index) = self.map.get(&id) {
+                    self.battles[index].mute(id, &nickname, muted);
+                }
+            }
+            PhysicsCommand::Rematch { id, accept, conn } => {
+                self.close_lobbies(|f| f.expired());
+                if let Some(index) = self.lobbies.iter().position(|f| f.contains(id)) {
+                    if !accept {
+                        let lobby = self.lobbies.swap_remove(index);
+                        lobby.decline(id);
+                        self.close_lobby(&lobby);
+                    } else if self.lobbies[index].accept(id, conn) {
+                        let lobby = self.lobbies.swap_remove(index);
+                        self.close_lobby(&lobby);
+                        start_rematch(lobby);
+                    }
+                }
+            }
+            PhysicsCommand::NotifyPlayerAboutMatch { id, new_conn } => {
+                if let Some(&index) = self.map.get(&id) {
+                    let battle = &mut self.battles[index];
+                    if let Some(player) = battle.player_index(id) {
+                        //New client has no snapshots to apply deltas to
+                        battle.players[player].conn = Some(new_conn);
+                        battle.players[player].connected = true;
+                        battle.players[player].absent = 0f32;
+                        battle.players[player].ack_frame = None;
+                        battle.players[player].sent.clear();
+                        let data = battle
+                            .map_found_packet(player, 0f32.max(battle.time - battle.battle_time));
+                        battle.send_to(player, &data);
+                        for data in battle.pickup_packets() {
+                            battle.send_to(player, &data);
+                        }
+                    }
+                } else {
+                    send_packet(&new_conn, &Packet::MapNotFoundResponse);
+                }
+            }
+            PhysicsCommand::AddSpectator { id, target, conn } => {
+                //Without target the battle with most trophies is featured
+                let index = match target {
+                    Some(target) => self.map.get(&target).copied(),
+                    None => (0..self.battles.len()).max_by_key(|&f| trophies(&self.battles[f])),
+                };
+                match index {
+                    Some(index) => self.battles[index].add_spectator(id, conn),
+                    None => send_packet(&conn, &Packet::SpectateNotFoundResponse),
+                }
+            }
+            PhysicsCommand::RemoveSpectator { id } => {
+                for battle in self.battles.iter_mut() {
+                    battle.remove_spectator(id);
+                }
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let start = Instant::now();
+        let mut i = 0;
+        while i < self.battles.len() {
+            if self.battles[i].advance() {
+                self.end_battle(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        self.close_lobbies(|f| f.expired());
+
+        self.busy += start.elapsed();
+        let period = self.period.elapsed();
+        if period >= LOAD_PERIOD {
+            let busy = (self.busy.as_secs_f32() / period.as_secs_f32() * 1000f32) as u32;
+            self.load.busy.store(busy, Ordering::Relaxed);
+            self.busy = Duration::ZERO;
+            self.period = Instant::now();
+        }
+    }
+
+    fn end_battle(&mut self, index: usize) {
+        self.battles[index].store_anomalies();
+        self.battles[index].store_leavers();
+        self.battles[index].send_results(&mut self.gen);
+        self.battles[index].end_spectating();
+
+        for player in &self.battles[index].players {
+            self.map.remove(&player.player.id);
+        }
+        let battle = self.battles.swap_remove(index);
+        //Older lobby of a player is replaced, it could not be in two battles at once
+        self.close_lobbies(|f| battle.players.iter().any(|p| f.contains(p.player.id)));
+        let players = battle
+            .players
+            .iter()
+            .map(|f| BalancedPlayer(f.player.clone(), f.tank.id, f.conn.clone()))
+            .collect();
+        //Rematch is played on the same map only if the host chose it, public
+        //rematches avoid the last map like any other battle
+        let private = battle.private.clone();
+        let mut lobby = Lobby::new(battle.mode, players, private);
+        if let Some(moved) = self.battles.get(index) {
+            for player in &moved.players {
+                self.map.insert(player.player.id, index);
+            }
+        }
+        self.load.battles.fetch_sub(1, Ordering::Relaxed);
+        self.load
+            .players
+            .fetch_sub(battle.players.len(), Ordering::Relaxed);
+        self.update_featured();
+        let _ = self.finished.send(Finished::Battle(
+            battle.players.iter().map(|f| f.player.id).collect(),
+        ));
+
+        let players: Vec<Box<Player>> = battle
+            .players
+            .into_iter()
+            .filter(|f| f.bot.is_none())
+            .map(|f| f.player)
+            .collect();
+        let replay = battle.replay;
+        lobby.saved = Some(RUNTIME.get().spawn_blocking(move || {
+            for player in &players {
+                if let Err(e) = crate::db::save_battle_stats(player) {
+                    error!("failed to save player {}: {}", player.id, e);
+                }
+            }
+            if let (Some(replay), Some(dir)) = (replay, &CONFIG.get().replays_dir) {
+                if let Err(e) = replay.save(dir) {
+                    error!("failed to save replay: {}", e);
+                }
+            }
+        }));
+        self.lobbies.push(lobby);
+    }
+
+    fn close_lobbies<F: Fn(&Lobby) -> bool>(&mut self, condition: F) {
+        let (closed, open) = std::mem::take(&mut self.lobbies)
+            .into_iter()
+            .partition(|f| condition(f));
+        self.lobbies = open;
+        for lobby in closed {
+            self.close_lobby(&lobby);
+        }
+    }
+
+    //Rematch commands of its players are not routed here anymore
+    fn close_lobby(&self, lobby: &Lobby) {
+        let _ = self.finished.send(Finished::Lobby(self.index, lobby.ids()));
+    }
+
+    fn update_featured(&self) {
+        let featured = self.battles.iter().map(trophies).max().unwrap_or(i64::MIN);
+        self.load.featured.store(featured, Ordering::Relaxed);
+    }
+}
+
+//Profiles are loaded again once stats of the last battle are saved, they
+//could have changed since the battle. Match is created right away, players
+//leave the matchmaker if they joined it
+fn start_rematch(mut lobby: Lobby) {
+    let saved = lobby.saved.take();
+    RUNTIME.get().spawn(async move {
+        if let Some(saved) = saved {
+            let _ = saved.await;
+        }
+        let _ = tokio::task::spawn_blocking(move || {
+            let mut players = Vec::new();
+            for player in lobby.players {
+                if player.2.is_none() {
+                    players.push(player);
+                    continue;
+                }
+                match crate::db::get_player_by_id(player.0.id) {
+                    Some(profile) => {
+                        let _ = MATCHMAKER
+                            .get()
+                            .send(BalancerCommand::RemovePlayer(profile.id));
+                        players.push(BalancedPlayer(Box::new(profile), player.1, player.2));
+                    }
+                    None => return,
+                }
+            }
+            let cmd = PhysicsCommand::CreateMatch {
+                players,
+                mode: lobby.mode,
+                private: lobby.private,
+            };
+            let _ = PHYSICS.get().send(cmd);
+        })
+        .await;
+    });
+}
+
+fn trophies(battle: &Battle) -> i64 {
+    battle
+        .players
+        .iter()
+        .map(|f| f.player.trophies as i64)
+        .sum()
+}
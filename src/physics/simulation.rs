@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use super::{Assets, Battle, Result, WorldPlayer, DEFAULT_TICK_RATE, MAX_BATTLE_TIME};
+use crate::data::{
+    BattleMode, BattleResult, GamePlayerData, Map, Player, PlayerPosition, Tank, TankInfo, TANKS,
+};
+
+pub(super) const TICK_TIME: f32 = 1f32 / DEFAULT_TICK_RATE as f32;
+
+//Input of a simulated player, applied before the next step
+#[derive(Debug, Clone, Copy)]
+pub enum SimulationInput {
+    Move {
+        body_rotation: f32,
+        gun_rotation: f32,
+        moving: bool,
+    },
+    Shoot,
+    Ability,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SimulationOutcome {
+    pub ticks: u32,
+    pub winner: Option<u8>,
+    pub players: Vec<SimulationStats>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SimulationStats {
+    pub result: BattleResult,
+    pub hp: i32,
+    pub shots: i32,
+    pub succeeded_shots: i32,
+    pub damage_dealt: i32,
+    pub damage_taken: i32,
+}
+
+//Duel between two tanks without any connections. Battle starts immediately
+//and advances by `TICK_TIME` per step, so the same inputs and seed always
+//give the same outcome
+pub struct Simulation<'a> {
+    pub(super) battle: Battle<'a>,
+    pub(super) gen: StdRng,
+    pub(super) tick: u32,
+}
+
+impl<'a> Simulation<'a> {
+    fn new(tanks: [&'a TankInfo; 2], map: &'a Map, assets: &'a Assets, seed: u64) -> Self {
+        let players = tanks
+            .iter()
+            .enumerate()
+            .map(|(index, tank_info)| {
+                let id = index as i64 + 1;
+                let nickname = tank_info.characteristics.name.clone();
+                let tank = Tank {
+                    id: tank_info.id as i32,
+                    level: 1,
+                    count: 0,
+                };
+                WorldPlayer::new(
+                    Box::new(Player::unregistered(id, nickname)),
+                    tank,
+                    tank_info,
+                    None,
+                )
+            })
+            .collect();
+        let mut battle = Battle::new(players, BattleMode::Duel, map, assets, seed);
+        //nobody has to load the map
+        battle.time = MAX_BATTLE_TIME;
+        Self {
+            battle,
+            gen: StdRng::seed_from_u64(seed),
+            tick: 0,
+        }
+    }
+
+    pub fn state(&self, index: usize) -> GamePlayerData {
+        self.battle.player_data(index, true, &HashMap::new())
+    }
+
+    pub fn input(&mut self, index: usize, input: SimulationInput) {
+        let id = self.battle.players[index].player.id;
+        match input {
+            SimulationInput::Move {
+                body_rotation,
+                gun_rotation,
+                moving,
+            } => self.battle.move_player(
+                id,
+                PlayerPosition {
+                    frame_num: (self.tick + 1) as u16,
+                    body_rotation,
+                    gun_rotation,
+                    moving,
+                    ack_frame: None,
+                },
+            ),
+            SimulationInput::Shoot => self.battle.shoot(id),
+            SimulationInput::Ability => self.battle.use_ability(id),
+        }
+    }
+
+    //Returns true when the battle is over
+    pub fn step(&mut self) -> bool {
+        self.tick += 1;
+        self.battle.update(TICK_TIME)
+    }
+
+    //Controller is asked for inputs before every step
+    pub fn run<F>(mut self, mut controller: F) -> SimulationOutcome
+    where
+        F: FnMut(&Self) -> Vec<(usize, SimulationInput)>,
+    {
+        loop {
+            for (index, input) in controller(&self) {
+                self.input(index, input);
+            }
+            if self.step() {
+                break;
+            }
+        }
+        self.outcome()
+    }
+
+    //Script entries are (tick, player index, input)
+    #[cfg(test)]
+    pub fn run_script(self, script: &[(u32, usize, SimulationInput)]) -> SimulationOutcome {
+        self.run(|sim| {
+            script
+                .iter()
+                .filter(|f| f.0 == sim.tick)
+                .map(|f| (f.1, f.2))
+                .collect()
+        })
+    }
+
+    fn outcome(&mut self) -> SimulationOutcome {
+        let results = self.battle.results(&mut self.gen);
+        SimulationOutcome {
+            ticks: self.tick,
+            winner: self.battle.winner(),
+            players: self
+                .battle
+                .players
+                .iter()
+                .zip(results)
+                .map(|(player, results)| SimulationStats {
+                    result: results.result,
+                    hp: player.stats.hp,
+                    shots: player.stats.shots,
+                    succeeded_shots: player.stats.succeeded_shots,
+                    damage_dealt: player.stats.damage_dealt,
+                    damage_taken: player.stats.damage_taken,
+                })
+                .collect(),
+        }
+    }
+}
+
+//Both tanks stand still, turn guns to each other, shoot once reloaded and use
+//abilities as soon as they are ready
+pub fn aim_and_shoot(sim: &Simulation) -> Vec<(usize, SimulationInput)> {
+    let mut inputs = Vec::new();
+    for index in 0..2 {
+        let me = sim.state(index);
+        let target = sim.state(1 - index);
+        inputs.push((
+            index,
+            SimulationInput::Move {
+                body_rotation: 0f32,
+                gun_rotation: (target.x - me.x).atan2(me.y - target.y),
+                moving: false,
+            },
+        ));
+        let ability = &sim.battle.players[index].tank_info.characteristics.ability;
+        if ability.is_some() && me.ability_cool_down == 0f32 {
+            inputs.push((index, SimulationInput::Ability));
+        }
+        if me.cool_down == 0f32 {
+            inputs.push((index, SimulationInput::Shoot));
+        }
+    }
+    inputs
+}
+
+//Plays every pair of tanks against each other on every map and prints win rates
+pub fn balance_sweep(rounds: u32) -> Result<()> {
+    let assets = Assets::load()?;
+    let mut tanks: Vec<&TankInfo> = TANKS.get().iter().collect();
+    tanks.sort_by_key(|f| f.id);
+    for (i, first) in tanks.iter().enumerate() {
+        for second in &tanks[i + 1..] {
+            let (mut wins, mut losses, mut draws, mut ticks) = (0, 0, 0, 0);
+            for map in &assets.maps {
+                for seed in 0..rounds {
+                    //swap sides every round so spawn position does not matter
+                    let (pair, ours) = if seed % 2 == 0 {
+                        ([*first, *second], 0)
+                    } else {
+                        ([*second, *first], 1)
+                    };
+                    let outcome =
+                        Simulation::new(pair, map, &assets, seed as u64).run(aim_and_shoot);
+                    ticks += outcome.ticks;
+                    match outcome.winner {
+                        Some(team) if team == ours => wins += 1,
+                        Some(_) => losses += 1,
+                        None => draws += 1,
+                    }
+                }
+            }
+            let battles = (rounds as usize * assets.maps.len()).max(1);
+            println!(
+                "{} vs {}: {} wins, {} losses, {} draws, {:.1}s average",
+                first.characteristics.name,
+                second.characteristics.name,
+                wins,
+                losses,
+                draws,
+                ticks as f32 * TICK_TIME / battles as f32
+            );
+        }
+    }
+    Ok(())
+}
+
+//Assets and tanks sorted by id, so tests of every module get the same duel
+#[cfg(test)]
+pub(super) struct Fixture {
+    pub assets: Assets,
+    pub tanks: Vec<TankInfo>,
+}
+
+#[cfg(test)]
+impl Fixture {
+    pub fn load() -> Self {
+        let mut tanks = super::load_tanks("Tanks").unwrap();
+        tanks.sort_by_key(|f| f.id);
+        Self {
+            assets: Assets::load().unwrap(),
+            tanks,
+        }
+    }
+
+    //First two tanks on the first map
+    pub fn duel(&self) -> Simulation<'_> {
+        Simulation::new(
+            [&self.tanks[0], &self.tanks[1]],
+            &self.assets.maps[0],
+            &self.assets,
+            0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_tanks_draw_when_time_is_over() {
+        let fixture = Fixture::load();
+        let outcome = fixture.duel().run_script(&[]);
+        assert_eq!(outcome.winner, None);
+        assert_eq!(outcome.ticks, (MAX_BATTLE_TIME / TICK_TIME).round() as u32);
+        for (player, tank) in outcome.players.iter().zip(&fixture.tanks) {
+            assert_eq!(player.result, BattleResult::Draw);
+            assert_eq!(player.hp, tank.characteristics.hp as i32);
+            assert_eq!(player.shots, 0);
+        }
+    }
+
+    #[test]
+    fn test_shots_are_counted() {
+        let fixture = Fixture::load();
+        //guns are loaded only after the first reload, further shots are
+        //rejected until the next one
+        let reload = (fixture.tanks[0].characteristics.reloading / TICK_TIME).ceil() as u32;
+        let script: Vec<_> = (0..reload + reload / 2)
+            .map(|tick| (tick, 0, SimulationInput::Shoot))
+            .collect();
+        let outcome = fixture.duel().run_script(&script);
+        assert_eq!(outcome.players[0].shots, 1);
+        assert_eq!(outcome.players[1].shots, 0);
+    }
+
+    #[test]
+    fn test_simulation_is_deterministic() {
+        let fixture = Fixture::load();
+        let run = || {
+            let tanks = [&fixture.tanks[0], &fixture.tanks[1]];
+            Simulation::new(tanks, &fixture.assets.maps[0], &fixture.assets, 7).run(aim_and_shoot)
+        };
+        let outcome = run();
+        assert!(outcome.players.iter().any(|f| f.succeeded_shots > 0));
+        assert_eq!(outcome, run());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rmp_serde::Serializer;
+use serde::Serialize;
+
+use super::Battle;
+use crate::{
+    data::{BattlePhase, BulletData, BulletDelta, GamePacket, PlayerDelta, SnapshotPacket},
+    network::EXPECTED_MTU,
+};
+
+//Sent states kept per player, older acknowledgements get a full snapshot
+const SNAPSHOTS_KEPT: usize = 32;
+const POSITION_SCALE: f32 = 4f32;
+const ROTATION_SCALE: f32 = 65536f32 / 360f32;
+const COOL_DOWN_SCALE: f32 = 100f32;
+const FULL_TURN: i32 = 65536;
+const PLAYER_VALUES: usize = 11;
+
+//Quantised state as the player has it after receiving a snapshot
+#[derive(Default)]
+pub(super) struct SentState {
+    frame: u16,
+    //x, y, body rotation, gun rotation, hp, cool down, visible, effect, effect time,
+    //ability time, ability cool down
+    players: Vec<[i32; PLAYER_VALUES]>,
+    //Owner and x, y, rotation by bullet id
+    bullets: BTreeMap<u32, (u8, [i32; 3])>,
+}
+
+impl SentState {
+    fn new(frame: u16, packet: &GamePacket) -> Self {
+        let mut state = Self {
+            frame,
+            ..Default::default()
+        };
+        let players = std::iter::once(&packet.my_data).chain(&packet.others_data);
+        for (owner, data) in players.enumerate() {
+            state.players.push([
+                position(data.x),
+                position(data.y),
+                rotation(data.body_rotation),
+                rotation(data.gun_rotation),
+                data.hp as i32,
+                (data.cool_down * COOL_DOWN_SCALE).round() as i32,
+                data.visible as i32,
+                data.effect.map_or(0, |f| f.kind as i32 + 1),
+                data.effect
+                    .map_or(0, |f| (f.time_left * COOL_DOWN_SCALE).round() as i32),
+                (data.ability_time * COOL_DOWN_SCALE).round() as i32,
+                (data.ability_cool_down * COOL_DOWN_SCALE).round() as i32,
+            ]);
+            for bullet in &data.bullets {
+                let values = [
+                    position(bullet.x),
+                    position(bullet.y),
+                    rotation(bullet.rotation),
+                ];
+                state.bullets.insert(bullet.id, (owner as u8, values));
+            }
+        }
+        state
+    }
+}
+
+fn position(pixels: f32) -> i32 {
+    (pixels * POSITION_SCALE).round() as i32
+}
+
+fn rotation(degrees: f32) -> i32 {
+    (degrees.rem_euclid(360f32) * ROTATION_SCALE).round() as i32 % FULL_TURN
+}
+
+//Rotations wrap around, so turning over zero gives a small difference
+fn difference(new: i32, old: i32, wrap: bool) -> i32 {
+    let diff = new - old;
+    if wrap {
+        (diff + FULL_TURN / 2).rem_euclid(FULL_TURN) - FULL_TURN / 2
+    } else {
+        diff
+    }
+}
+
+fn player_delta(new: &[i32; PLAYER_VALUES], old: Option<&[i32; PLAYER_VALUES]>) -> PlayerDelta {
+    let old = old.copied().unwrap_or_default();
+    let diff: Vec<Option<i32>> = (0..PLAYER_VALUES)
+        .map(|i| Some(difference(new[i], old[i], i == 2 || i == 3)).filter(|&f| f != 0))
+        .collect();
+    PlayerDelta {
+        x: diff[0],
+        y: diff[1],
+        body_rotation: diff[2],
+        gun_rotation: diff[3],
+        hp: diff[4],
+        cool_down: diff[5],
+        visible: diff[6].map(|_| new[6] == 1),
+        effect: diff[7].map(|_| new[7] as u8),
+        effect_time: diff[8],
+        ability_time: diff[9],
+        ability_cool_down: diff[10],
+    }
+}
+
+fn serialize<T: Serialize>(packet: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut serializer = Serializer::new(&mut buf);
+    packet.serialize(&mut serializer).unwrap();
+    buf
+}
+
+//Serializes the packet, calling `shrink` until it fits in `limit` or there is
+//nothing left to drop
+pub(super) fn fit<T: Serialize>(
+    packet: &mut T,
+    limit: usize,
+    mut shrink: impl FnMut(&mut T) -> bool,
+) -> Vec<u8> {
+    let mut buf = serialize(packet);
+    while buf.len() > limit && shrink(packet) {
+        buf = serialize(packet);
+    }
+    buf
+}
+
+//Returns the datagram and the state player gets after applying it. Bullets
+//that don't fit in `limit` keep their base values and are sent next time
+fn encode(
+    time_left: u16,
+    phase: BattlePhase,
+    mut state: SentState,
+    base: Option<&SentState>,
+    limit: usize,
+) -> (Vec<u8>, SentState) {
+    let empty = SentState::default();
+    let old = base.unwrap_or(&empty);
+    let mut packet = SnapshotPacket {
+        frame_num: state.frame,
+        base_frame: base.map(|f| f.frame),
+        time_left,
+        phase,
+        players: state
+            .players
+            .iter()
+            .enumerate()
+            .map(|(i, f)| player_delta(f, old.players.get(i)))
+            .collect(),
+        bullets: state
+            .bullets
+            .iter()
+            .filter_map(|(&id, &(owner, new))| match old.bullets.get(&id) {
+                Some((_, old)) => {
+                    let diff: Vec<i32> =
+                        (0..3).map(|i| difference(new[i], old[i], i == 2)).collect();
+                    (diff != [0, 0, 0]).then(|| BulletDelta {
+                        id,
+                        owner: None,
+                        x: diff[0],
+                        y: diff[1],
+                        rotation: diff[2],
+                    })
+                }
+                None => Some(BulletDelta {
+                    id,
+                    owner: Some(owner),
+                    x: new[0],
+                    y: new[1],
+                    rotation: new[2],
+                }),
+            })
+            .collect(),
+        removed_bullets: old
+            .bullets
+            .keys()
+            .filter(|f| !state.bullets.contains_key(f))
+            .copied()
+            .collect(),
+    };
+
+    let buf = fit(&mut packet, limit, |packet| match packet.bullets.pop() {
+        Some(bullet) => {
+            match old.bullets.get(&bullet.id) {
+                Some(old) => state.bullets.insert(bullet.id, *old),
+                None => state.bullets.remove(&bullet.id),
+            };
+            true
+        }
+        None => false,
+    });
+    (buf, state)
+}
+
+impl Battle<'_> {
+    //Delta against the last snapshot player acknowledged, full one if it is unknown
+    pub(super) fn send_snapshot(&mut self, index: usize, bullets: &HashMap<i64, Vec<BulletData>>) {
+        let conn = match &self.players[index].conn {
+            Some(conn) => conn.clone(),
+            None => return,
+        };
+        let state = SentState::new(self.frame, &self.game_packet(index, bullets));
+        let player = &self.players[index];
+        let base = player
+            .ack_frame
+            .and_then(|ack| player.sent.iter().find(|f| f.frame == ack));
+        let limit = conn
+            .max_datagram_size()
+            .map_or(EXPECTED_MTU, |f| f.min(EXPECTED_MTU));
+        let (buf, state) = encode(self.time as u16, self.phase, state, base, limit);
+
+        let player = &mut self.players[index];
+        player.sent.push_back(state);
+        if player.sent.len() > SNAPSHOTS_KEPT {
+            player.sent.pop_front();
+        }
+        if conn.send_datagram(bytes::Bytes::from(buf)).is_err() {
+            player.connected = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{ActiveEffect, GamePlayerData, PickupKind};
+    use rmp_serde::Deserializer;
+    use serde::Deserialize;
+
+    fn player(x: f32, body_rotation: f32, bullets: Vec<BulletData>) -> GamePlayerData {
+        GamePlayerData {
+            visible: true,
+            x,
+            y: 100f32,
+            body_rotation,
+            gun_rotation: 0f32,
+            hp: 100,
+            cool_down: 0.5f32,
+            bullets,
+            effect: None,
+            ability_time: 0f32,
+            ability_cool_down: 3f32,
+        }
+    }
+
+    fn bullet(id: u32, x: f32) -> BulletData {
+        BulletData {
+            id,
+            x,
+            y: 10f32,
+            rotation: 90f32,
+        }
+    }
+
+    //What client does with the received datagram
+    fn apply(base: &SentState, buf: &[u8]) -> SentState {
+        let packet = SnapshotPacket::deserialize(&mut Deserializer::new(buf)).unwrap();
+        let mut state = SentState {
+            frame: packet.frame_num,
+            players: base.players.clone(),
+            bullets: base.bullets.clone(),
+        };
+        state
+            .players
+            .resize(packet.players.len(), [0; PLAYER_VALUES]);
+        for (values, delta) in state.players.iter_mut().zip(&packet.players) {
+            let diff = [
+                delta.x,
+                delta.y,
+                delta.body_rotation,
+                delta.gun_rotation,
+                delta.hp,
+                delta.cool_down,
+                delta.visible.map(|f| f as i32 - values[6]),
+                delta.effect.map(|f| f as i32 - values[7]),
+                delta.effect_time,
+                delta.ability_time,
+                delta.ability_cool_down,
+            ];
+            for i in 0..PLAYER_VALUES {
+                values[i] += diff[i].unwrap_or_default();
+                if i == 2 || i == 3 {
+                    values[i] = values[i].rem_euclid(FULL_TURN);
+                }
+            }
+        }
+        for id in &packet.removed_bullets {
+            state.bullets.remove(id);
+        }
+        for delta in &packet.bullets {
+            let entry = state.bullets.entry(delta.id).or_insert((0, [0; 3]));
+            if let Some(owner) = delta.owner {
+                *entry = (owner, [0; 3]);
+            }
+            entry.1[0] += delta.x;
+            entry.1[1] += delta.y;
+            entry.1[2] = (entry.1[2] + delta.rotation).rem_euclid(FULL_TURN);
+        }
+        state
+    }
+
+    #[test]
+    fn test_delta_reproduces_state() {
+        let first = GamePacket {
+            time_left: 100,
+            frame_num: 1,
+            my_data: GamePlayerData {
+                effect: Some(ActiveEffect {
+                    kind: PickupKind::Shield,
+                    time_left: 4f32,
+                }),
+                ..player(50f32, 359f32, vec![bullet(0, 5f32), bullet(1, 7f32)])
+            },
+            others_data: vec![player(70f32, 10f32, Vec::new())],
+            phase: BattlePhase::Regular,
+        };
+        let second = GamePacket {
+            time_left: 100,
+            frame_num: 2,
+            my_data: GamePlayerData {
+                effect: Some(ActiveEffect {
+                    kind: PickupKind::Shield,
+                    time_left: 3.5f32,
+                }),
+                ability_time: 1.5f32,
+                ..player(52f32, 1f32, vec![bullet(1, 9f32)])
+            },
+            others_data: vec![GamePlayerData {
+                bullets: vec![bullet(2, 3f32)],
+                ..Default::default()
+            }],
+            phase: BattlePhase::Overtime,
+        };
+        let (buf, sent) = encode(
+            100,
+            BattlePhase::Regular,
+            SentState::new(1, &first),
+            None,
+            EXPECTED_MTU,
+        );
+        let received = apply(&SentState::default(), &buf);
+        assert_eq!(received.players, sent.players);
+        assert_eq!(received.bullets, sent.bullets);
+
+        let (delta, sent) = encode(
+            100,
+            BattlePhase::Overtime,
+            SentState::new(2, &second),
+            Some(&sent),
+            EXPECTED_MTU,
+        );
+        assert!(delta.len() < buf.len());
+        let received = apply(&received, &delta);
+        assert_eq!(received.players, sent.players);
+        assert_eq!(received.bullets, sent.bullets);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use quinn::{Connection, SendDatagramError};
+
+use super::{send_packet, snapshot::fit, Battle};
+use crate::{
+    data::{BattleParticipant, BulletData, GamePlayerData, Packet, SpectatorPacket},
+    network::EXPECTED_MTU,
+};
+
+const MAX_SPECTATORS: usize = 100;
+
+pub(super) struct Spectator {
+    pub id: i64,
+    pub conn: Connection,
+}
+
+impl Battle<'_> {
+    pub(super) fn add_spectator(&mut self, id: i64, conn: Connection) {
+        if self.spectators.len() >= MAX_SPECTATORS {
+            send_packet(&conn, &Packet::SpectateNotFoundResponse);
+            return;
+        }
+        let data = Packet::SpectateResponse {
+            map: self.map.clone(),
+            mode: self.mode,
+            participants: self
+                .players
+                .iter()
+                .map(|f| BattleParticipant {
+                    nickname: f.player.nickname.clone().unwrap_or_default(),
+                    tank: f.tank.clone(),
+                    team: f.team,
+                })
+                .collect(),
+            initial_packet: self.spectator_packet(&HashMap::new()),
+            destroyed_objects: self.destroyed_objects.clone(),
+        };
+        send_packet(&conn, &data);
+        for data in self.pickup_packets() {
+            send_packet(&conn, &data);
+        }
+        self.spectators.push(Spectator { id, conn });
+    }
+
+    pub(super) fn remove_spectator(&mut self, id: i64) {
+        self.spectators.retain(|f| f.id != id);
+    }
+
+    //Neutral view of the battle, nobody's cooldown is revealed
+    fn spectator_packet(&self, bullets: &HashMap<i64, Vec<BulletData>>) -> SpectatorPacket {
+        SpectatorPacket {
+            time_left: self.time as u16,
+            frame_num: self.frame,
+            players_data: (0..self.players.len())
+                .map(|f| self.spectated_player_data(f, bullets))
+                .collect(),
+            phase: self.phase,
+        }
+    }
+
+    //Tank is shown only when its enemies see it, so a spectator can't tell a
+    //team where hidden enemies are
+    fn spectated_player_data(
+        &self,
+        index: usize,
+        bullets: &HashMap<i64, Vec<BulletData>>,
+    ) -> GamePlayerData {
+        let team = self.players[index].team;
+        let enemies: Vec<usize> = (0..self.players.len())
+            .filter(|&f| self.players[f].team != team)
+            .collect();
+        if enemies.is_empty() || enemies.iter().any(|&f| self.is_visible(f, index)) {
+            self.player_data(index, false, bullets)
+        } else {
+            self.hidden_player_data(index, &self.visible_bullets(enemies[0], bullets))
+        }
+    }
+
+    //Bullets that don't fit in a datagram are dropped. Spectators are dropped
+    //only when their connection is lost
+    pub(super) fn send_spectators_state(&mut self, bullets: &HashMap<i64, Vec<BulletData>>) {
+        if self.spectators.is_empty() {
+            return;
+        }
+        let limit = self
+            .spectators
+            .iter()
+            .map(|f| {
+                f.conn
+                    .max_datagram_size()
+                    .map_or(EXPECTED_MTU, |f| f.min(EXPECTED_MTU))
+            })
+            .min()
+            .unwrap_or(EXPECTED_MTU);
+        let mut packet = self.spectator_packet(bullets);
+        let buf = fit(&mut packet, limit, |packet| {
+            packet
+                .players_data
+                .iter_mut()
+                .max_by_key(|f| f.bullets.len())
+                .and_then(|f| f.bullets.pop())
+                .is_some()
+        });
+        let buf = bytes::Bytes::from(buf);
+        self.spectators.retain(|f| {
+            !matches!(
+                f.conn.send_datagram(buf.clone()),
+                Err(SendDatagramError::ConnectionLost(_))
+            )
+        });
+    }
+
+    pub(super) fn end_spectating(&mut self) {
+        let winner_team = self.winner();
+        for spectator in self.spectators.drain(..) {
+            send_packet(
+                &spectator.conn,
+                &Packet::SpectateEndResponse { winner_team },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::physics::{loopback, simulation::Fixture, RUNTIME};
+
+    #[test]
+    fn test_spectators_see_only_what_enemies_see() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        //The second tank hides from the first one
+        sim.battle.visibility = vec![vec![true, false], vec![true, true]];
+        let packet = sim.battle.spectator_packet(&HashMap::new());
+        assert!(packet.players_data[0].visible);
+        assert!(!packet.players_data[1].visible);
+        assert_eq!(packet.players_data[1].x, 0f32);
+    }
+
+    #[test]
+    fn test_spectator_state_fits_in_datagram() {
+        let fixture = Fixture::load();
+        let mut sim = fixture.duel();
+        let (conn, mut client) = loopback();
+        sim.battle.add_spectator(1, conn);
+        let bullets = (0..500)
+            .map(|id| BulletData {
+                id,
+                x: 100f32,
+                y: 100f32,
+                rotation: 90f32,
+            })
+            .collect();
+        let owner = sim.battle.players[0].player.id;
+        sim.battle
+            .send_spectators_state(&HashMap::from([(owner, bullets)]));
+        assert_eq!(sim.battle.spectators.len(), 1);
+        let datagram = RUNTIME.get().block_on(async {
+            let timeout = std::time::Duration::from_secs(5);
+            tokio::time::timeout(timeout, client.datagrams.next()).await
+        });
+        let datagram = datagram.unwrap().unwrap().unwrap();
+        assert!(datagram.len() <= EXPECTED_MTU);
+    }
+}
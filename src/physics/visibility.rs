@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use rapier2d::prelude::*;
+
+use super::{Battle, SCALE_TO_PHYSICS};
+use crate::data::{AbilityKind, BulletData, GamePlayerData, MapObject};
+
+//Enemies closer than this see a tank in a bush, in pixels
+const REVEAL_DISTANCE: f32 = 150f32;
+//Seconds a tank stays visible in a bush after a shot
+pub(super) const REVEAL_TIME: f32 = 1.5f32;
+
+pub(super) struct Bush {
+    center: Vector<Real>,
+    radius: Real,
+}
+
+impl Bush {
+    pub(super) fn new(object: &MapObject, size: Point<Real>) -> Self {
+        Self {
+            center: vector![object.x + size.x / 2f32, object.y + size.y / 2f32] * SCALE_TO_PHYSICS,
+            radius: size.x * object.scale / 2f32 * SCALE_TO_PHYSICS,
+        }
+    }
+}
+
+//Tanks are hidden behind walls and map objects, in smoke, and in bushes unless
+//they shot recently. Enemies close to a hidden tank see it. Team members share
+//what they see
+impl Battle<'_> {
+    pub(super) fn update_visibility(&mut self) {
+        self.world.query_pipeline.update(
+            &self.world.islands,
+            &self.world.bodies,
+            &self.world.colliders,
+        );
+        let positions: Vec<Vector<Real>> = self
+            .players
+            .iter()
+            .map(|f| *self.world.bodies[f.handle].translation())
+            .collect();
+        let concealed: Vec<bool> = self
+            .players
+            .iter()
+            .zip(&positions)
+            .map(|(player, position)| {
+                player.ability_active(AbilityKind::SmokeScreen)
+                    || (player.revealed <= 0f32
+                        && self
+                            .bushes
+                            .iter()
+                            .any(|f| (position - f.center).norm() <= f.radius))
+            })
+            .collect();
+
+        let count = self.players.len();
+        let mut sees = vec![vec![false; count]; count];
+        for observer in 0..count {
+            for target in 0..count {
+                let distance = (positions[target] - positions[observer]).norm();
+                sees[observer][target] = observer == target
+                    || ((!concealed[target] || distance <= REVEAL_DISTANCE * SCALE_TO_PHYSICS)
+                        && self.line_of_sight(positions[observer], positions[target]));
+            }
+        }
+        self.visibility = (0..count)
+            .map(|observer| {
+                (0..count)
+                    .map(|target| {
+                        self.teammates(observer)
+                            .any(|f| f == target || sees[f][target])
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+
+    //Dead tanks don't see anything, except for the observer itself
+    fn teammates(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        let team = self.players[index].team;
+        (0..self.players.len()).filter(move |&f| {
+            f == index || (self.players[f].team == team && self.players[f].stats.hp > 0)
+        })
+    }
+
+    fn line_of_sight(&self, from: Vector<Real>, to: Vector<Real>) -> bool {
+        let distance = (to - from).norm();
+        if distance == 0f32 {
+            return true;
+        }
+        let ray = Ray::new(from.into(), (to - from) / distance);
+        self.world
+            .query_pipeline
+            .cast_ray(
+                &self.world.bodies,
+                &self.world.colliders,
+                &ray,
+                distance,
+                true,
+                QueryFilter::only_fixed().exclude_sensors(),
+            )
+            .is_none()
+    }
+
+    //Everything is visible until visibility is calculated for the first time
+    pub(super) fn is_visible(&self, observer: usize, target: usize) -> bool {
+        self.visibility.is_empty() || self.visibility[observer][target]
+    }
+
+    //Bullets of the observer's team and enemy bullets in line of sight of the team
+    pub(super) fn visible_bullets(
+        &self,
+        observer: usize,
+        bullets: &HashMap<i64, Vec<BulletData>>,
+    ) -> HashMap<i64, Vec<BulletData>> {
+        if self.visibility.is_empty() {
+            return bullets.clone();
+        }
+        let team = self.players[observer].team;
+        let eyes: Vec<Vector<Real>> = self
+            .teammates(observer)
+            .map(|f| *self.world.bodies[self.players[f].handle].translation())
+            .collect();
+        bullets
+            .iter()
+            .map(|(&owner, list)| {
+                let own = self
+                    .player_index(owner)
+                    .is_some_and(|f| self.players[f].team == team);
+                let list = list
+                    .iter()
+                    .filter(|bullet| {
+                        let position = vector![bullet.x, bullet.y] * SCALE_TO_PHYSICS;
+                        own || eyes.iter().any(|&f| self.line_of_sight(f, position))
+                    })
+                    .cloned()
+                    .collect();
+                (owner, list)
+            })
+            .collect()
+    }
+
+    //Hidden tank has only its visible bullets
+    pub(super) fn hidden_player_data(
+        &self,
+        index: usize,
+        bullets: &HashMap<i64, Vec<BulletData>>,
+    ) -> GamePlayerData {
+        GamePlayerData {
+            bullets: bullets
+                .get(&self.players[index].player.id)
+                .cloned()
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
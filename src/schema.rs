@@ -22,5 +22,21 @@ table! {
         trophies -> Int4,
         tanks -> Array<DbTank>,
         daily_items -> Array<DbDailyItem>,
+        last_map -> Nullable<Varchar>,
+        anomaly_score -> Float4,
+        flagged -> Bool,
+        leaves -> Int4,
+        surrenders -> Int4,
+        leave_streak -> Int4,
+        penalty_until -> Timestamp,
+    }
+}
+
+table! {
+    friendships (player_id, friend_id) {
+        player_id -> Int8,
+        friend_id -> Int8,
+        accepted -> Bool,
+        created -> Timestamp,
     }
 }